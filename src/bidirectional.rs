@@ -0,0 +1,206 @@
+use crate::corral::CorralCache;
+use crate::node::Position;
+use crate::optimal::{nodes_memory, SearchNode};
+use crate::sokoban::{Direction, Sokoban};
+use crate::solution::{Pull, Solution};
+use crate::solver::Solver;
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+// One end of the bidirectional search, pushing from the start or pulling from the goals
+struct Frontier {
+    expanded: usize,
+    sokoban: Sokoban,
+    heuristics: HashMap<usize, HashMap<Position, usize>>,
+    nodes: Vec<SearchNode>,
+    open: BinaryHeap<Reverse<(usize, usize, usize)>>,
+    // every state reached, the player zone and sorted boxes, with the node that got there
+    seen: HashMap<Box<[u32]>, usize>,
+}
+
+impl Frontier {
+    fn new(sokoban: Sokoban, players: &[Position]) -> Frontier {
+        let mut frontier = Frontier {
+            expanded: 0,
+            heuristics: Solver::build_heuristics(&sokoban),
+            sokoban,
+            nodes: Vec::new(),
+            open: BinaryHeap::new(),
+            seen: HashMap::new(),
+        };
+        for player in players.iter() {
+            frontier.sokoban.player = Some(*player);
+            frontier.add(None, None, 0);
+        }
+        frontier
+    }
+
+    // Keeps the current state of the world as a new node, unless it was already reached or
+    // can't be solved, returning its state
+    fn add(&mut self, parent: Option<usize>, pull: Option<Pull>, cost: usize) -> Option<Box<[u32]>> {
+        let state = self.sokoban.packed_state();
+        if self.seen.contains_key(&state) {
+            return None;
+        }
+        let estimate = Solver::matching_bound(&self.sokoban, &self.heuristics)?;
+        self.nodes.push(SearchNode {
+            boxes: self.sokoban.boxes().to_vec(),
+            player: self.sokoban.player.unwrap(),
+            cost,
+            parent,
+            pull,
+        });
+        self.seen.insert(state.clone(), self.nodes.len() - 1);
+        self.open.push(Reverse((cost + estimate, estimate, self.nodes.len() - 1)));
+        Some(state)
+    }
+
+    /*
+     * Expands the most promising node left, moving every box every way it can go. Returns
+     * the new node and the node of the other end when one of the states is already there.
+     */
+    fn expand(&mut self, other: &Frontier, corral_cache: &CorralCache) -> Option<(usize, usize)> {
+        let Reverse((_, _, index)) = self.open.pop()?;
+        self.expanded += 1;
+        self.sokoban.set_boxes(self.nodes[index].boxes.clone());
+        let (player, cost) = (self.nodes[index].player, self.nodes[index].cost);
+        self.sokoban.player = Some(player);
+
+        for box_index in 0..self.sokoban.boxes().len() {
+            for direction in Direction::ALL.iter() {
+                let box_position = self.sokoban.boxes()[box_index];
+                if !self.sokoban.move_box(box_index, direction) {
+                    continue;
+                }
+
+                let is_deadlock =
+                    self.sokoban.is_deadlock(box_index) || self.sokoban.is_corral_deadlock(box_index, corral_cache);
+                let pull = Pull {
+                    box_position,
+                    direction: *direction,
+                };
+                let state = if is_deadlock { None } else { self.add(Some(index), Some(pull), cost + 1) };
+                self.sokoban.undo_move_box(box_index, direction);
+                self.sokoban.player = Some(player);
+                if let Some(meeting) = state.and_then(|state| other.seen.get(&state)) {
+                    return Some((self.nodes.len() - 1, *meeting));
+                }
+            }
+        }
+        None
+    }
+}
+
+// How the two ends of a search met, the nodes each of them expanded and which one got there
+struct Meeting {
+    pushed: usize,
+    pulled: usize,
+    by_pulling: bool,
+}
+
+impl Solver {
+    /*
+     * Searches from both ends at once, pushing boxes from the start and pulling them from the
+     * goals, and stops when both ends get to the same state. Each end is ordered like the A*
+     * search, by moves done plus the lower bound of moves left, and they take turns. Two
+     * searches half as deep go through far fewer states than a single one, but the solution
+     * found is not the one with the fewest pushes.
+     *
+     * States are the player zone and the sorted box positions on both ends, so the player
+     * can walk from where the pushes left it to where the pulls start.
+     */
+    pub fn solve_bidirectional(&mut self) -> Option<Solution> {
+        self.meet_in_the_middle(false).map(|(solution, _)| solution)
+    }
+
+    // Both ends taking turns, starting with the pushing one if pushing_first
+    fn meet_in_the_middle(&mut self, pushing_first: bool) -> Option<(Solution, Meeting)> {
+        let mut pushing = Frontier::new(self.start.clone(), &[self.original_player]);
+        let reverse = self.start.reverse();
+        let zones = Solver::zones_of(&reverse);
+        let mut pulling = Frontier::new(reverse, &zones);
+
+        // the level may already be solved
+        let mut meeting = pushing
+            .seen
+            .iter()
+            .find_map(|(state, push_node)| pulling.seen.get(state).map(|pull_node| (*push_node, *pull_node)));
+        let mut pushing_turn = !pushing_first;
+        while meeting.is_none() {
+            if pushing.open.is_empty() || pulling.open.is_empty() {
+                return None;
+            }
+            if self.limit_reached(nodes_memory(pushing.nodes.len() + pulling.nodes.len(), self.start.boxes().len())) {
+                return None;
+            }
+            self.counter += 1;
+            pushing_turn = !pushing_turn;
+            meeting = if pushing_turn {
+                pushing.expand(&pulling, &self.corral_cache)
+            } else {
+                pulling.expand(&pushing, &self.corral_cache).map(|(pull_node, push_node)| (push_node, pull_node))
+            };
+        }
+
+        let (push_node, pull_node) = meeting.unwrap();
+        let meeting = Meeting {
+            pushed: pushing.expanded,
+            pulled: pulling.expanded,
+            by_pulling: !pushing_turn,
+        };
+        debug!(
+            "both ends met after {} pushes and {} pulls, {} and {} nodes expanded, found {}",
+            pushing.nodes[push_node].cost,
+            pulling.nodes[pull_node].cost,
+            meeting.pushed,
+            meeting.pulled,
+            if meeting.by_pulling { "pulling" } else { "pushing" }
+        );
+        let mut pushes = Solver::to_pushes(&Solver::trace_pulls(&pushing.nodes, push_node));
+        let pulls = Solver::trace_pulls(&pulling.nodes, pull_node);
+        pushes.extend(pulls.iter().rev().map(|pull| pull.to_push()));
+        Some((Solution::from_pushes(self.original_player, &pushes), meeting))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bidirectional_solver() {
+        let sokoban_level = String::from("0706111100102100100111154001100301100111111100");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_bidirectional().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+        // never fewer than the push optimal solution
+        assert!(solution.pushes().len() >= 8);
+    }
+
+    #[test]
+    fn test_bidirectional_solver_meets_from_either_end() {
+        let level = "########|#@$   .#|########";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut by_pulling = Vec::new();
+        for pushing_first in [true, false].iter() {
+            let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+            let (solution, meeting) = solver.meet_in_the_middle(*pushing_first).unwrap();
+            assert!(solution.verify(&sokoban));
+            assert_eq!(solution.pushes().len(), 4);
+            assert!(meeting.pushed > 0 && meeting.pulled > 0);
+            by_pulling.push(meeting.by_pulling);
+        }
+        // the solution is stitched right whichever end reached the other
+        assert!(by_pulling.contains(&true) && by_pulling.contains(&false));
+    }
+
+    #[test]
+    fn test_bidirectional_solver_with_player_zones() {
+        // the pulls start on either side of the boxes
+        let sokoban_level = String::from("0607111111110000011010101120354110001111111100");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_bidirectional().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::error::LevelError;
+use crate::sokoban::Sokoban;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum CollectionError {
+    Io(io::Error),
+    // line is where the level starts in the file, counting from 1, with the title before it
+    Level {
+        line: usize,
+        title: Option<String>,
+        error: LevelError,
+    },
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CollectionError::Io(error) => write!(f, "{}", error),
+            CollectionError::Level { line, error, .. } => write!(f, "level at line {}: {}", line, error),
+        }
+    }
+}
+
+impl Error for CollectionError {}
+
+impl From<io::Error> for CollectionError {
+    fn from(error: io::Error) -> CollectionError {
+        CollectionError::Io(error)
+    }
+}
+
+/*
+ * A level read from a collection file, with the metadata the file had around it.
+ *
+ * 1
+ * ####
+ * # .#
+ * #  ###
+ * #*@  #
+ * #  $ #
+ * #  ###
+ * ####
+ * Title: 1
+ * Author: David W. Skinner
+ * Solution (Moves/Pushes): 33/8
+ * dlUrrrdLullddrUluRuulDrddrruLdlUU
+ */
+#[derive(Clone, Debug)]
+pub struct Level {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    pub solutions: Vec<String>,
+    pub sokoban: Sokoban,
+}
+
+impl Level {
+    fn new(sokoban: Sokoban, title: Option<String>) -> Level {
+        Level {
+            title,
+            author: None,
+            comment: None,
+            solutions: Vec::new(),
+            sokoban,
+        }
+    }
+
+    // Name to show on results, the title if there is one, else the level in numeric format
+    pub fn name(&self) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => self.sokoban.print_level(),
+        }
+    }
+}
+
+enum Block {
+    None,
+    Comment,
+    Solution,
+}
+
+fn is_board_line(line: &str) -> bool {
+    line.contains('#') && line.chars().all(|c| "#@+$*.-_ ".contains(c))
+}
+
+// Lines in one of the numeric formats, like levels/microban_num.soko
+fn is_numeric_level(line: &str) -> bool {
+    if line.starts_with("v2:") {
+        return true;
+    }
+    if line.len() <= 4 || !line.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let height = line[0..2].parse::<usize>().unwrap();
+    let width = line[2..4].parse::<usize>().unwrap();
+    line.len() == 4 + height * width
+}
+
+fn is_solution_line(line: &str) -> bool {
+    line.chars().all(|c| "lurdLURD".contains(c))
+}
+
+fn parse_key(line: &str) -> Option<(String, &str)> {
+    let colon = line.find(':')?;
+    let key = &line[..colon];
+    if key.is_empty() || (key.contains(' ') && !key.starts_with("Solution")) {
+        return None;
+    }
+    Some((key.to_lowercase(), line[colon + 1..].trim()))
+}
+
+// A level of a collection, or why it couldn't be read
+pub type CollectionLevel = Result<Level, CollectionError>;
+
+// The level in the given text, keeping the title on it or on its error
+fn read_level(text: &str, line: usize, title: Option<String>, numeric: bool) -> CollectionLevel {
+    let sokoban = if numeric { Sokoban::new(text) } else { Sokoban::from_xsb(text) };
+    match sokoban {
+        Ok(sokoban) => Ok(Level::new(sokoban, title)),
+        Err(error) => Err(CollectionError::Level { line, title, error }),
+    }
+}
+
+/*
+ * Reads all levels of a collection, levels can be in XSB or in the numeric format,
+ * and be surrounded by titles and "Key: value" metadata. A plain text line before a
+ * level is taken as its title, "Title:", "Author:", "Comment:" and "Solution" after a
+ * level are set on it. A level that can't be read is kept as its error, and the ones
+ * after it are still read.
+ */
+pub fn parse_collection(text: &str) -> Vec<CollectionLevel> {
+    let mut levels: Vec<CollectionLevel> = Vec::new();
+    let mut board: Vec<&str> = Vec::new();
+    let mut board_line = 0;
+    let mut title: Option<String> = None;
+    let mut block = Block::None;
+
+    for (number, line) in text.lines().map(|line| line.trim_end()).enumerate() {
+        if is_board_line(line) {
+            if board.is_empty() {
+                board_line = number + 1;
+            }
+            board.push(line);
+            block = Block::None;
+            continue;
+        }
+
+        if !board.is_empty() {
+            levels.push(read_level(&board.join("\n"), board_line, title.take(), false));
+            board.clear();
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            block = Block::None;
+            continue;
+        }
+
+        if is_numeric_level(line) {
+            levels.push(read_level(line, number + 1, title.take(), true));
+            block = Block::None;
+            continue;
+        }
+
+        // metadata after a level that couldn't be read has nowhere to go
+        let level = levels.last_mut().and_then(|level| level.as_mut().ok());
+        match (&block, level) {
+            (Block::Comment, Some(level)) if line.to_lowercase() != "comment-end:" => {
+                let comment = level.comment.get_or_insert_with(String::new);
+                if !comment.is_empty() {
+                    comment.push('\n');
+                }
+                comment.push_str(line);
+                continue;
+            }
+            (Block::Solution, Some(level)) if is_solution_line(line) => {
+                level.solutions.last_mut().unwrap().push_str(line);
+                continue;
+            }
+            _ => block = Block::None,
+        }
+
+        let (key, value) = match parse_key(line) {
+            Some(key_value) => key_value,
+            None => {
+                title = Some(line.trim_start_matches(';').trim().to_string());
+                continue;
+            }
+        };
+
+        // metadata before the first level belongs to the collection
+        let level = match levels.last_mut().and_then(|level| level.as_mut().ok()) {
+            Some(level) => level,
+            None => continue,
+        };
+        match key.as_str() {
+            "title" => level.title = Some(value.to_string()),
+            "author" => level.author = Some(value.to_string()),
+            "comment" if value.is_empty() => block = Block::Comment,
+            "comment" => level.comment = Some(value.to_string()),
+            key if key.starts_with("solution") => {
+                let solution = if is_solution_line(value) { value } else { "" };
+                level.solutions.push(solution.to_string());
+                block = Block::Solution;
+            }
+            _ => {}
+        }
+    }
+
+    if !board.is_empty() {
+        levels.push(read_level(&board.join("\n"), board_line, title, false));
+    }
+    levels
+}
+
+pub fn read_collection<P: AsRef<Path>>(path: P) -> Result<Vec<CollectionLevel>, CollectionError> {
+    Ok(parse_collection(&fs::read_to_string(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_collection() {
+        let collection = "Microban\n\
+            Copyright: David W. Skinner\n\
+            \n\
+            1\n\
+            ####\n\
+            # .#\n\
+            #  ###\n\
+            #*@  #\n\
+            #  $ #\n\
+            #  ###\n\
+            ####\n\
+            Title: First\n\
+            Author: David W. Skinner\n\
+            Comment:\n\
+            a first\n\
+            level\n\
+            Comment-End:\n\
+            Solution (Moves/Pushes): 33/8\n\
+            dlUrrrdLullddrU\n\
+            luRuulDrddrruLdlUU\n\
+            \n\
+            ; 2\n\
+            \n\
+            ######\n\
+            #    #\n\
+            # #@ #\n\
+            # $* #\n\
+            # .* #\n\
+            #    #\n\
+            ######\n";
+
+        let levels: Vec<Level> = parse_collection(collection).into_iter().map(Result::unwrap).collect();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].title, Some(String::from("First")));
+        assert_eq!(levels[0].author, Some(String::from("David W. Skinner")));
+        assert_eq!(levels[0].comment, Some(String::from("a first\nlevel")));
+        assert_eq!(levels[0].solutions, vec![String::from("dlUrrrdLullddrUluRuulDrddrruLdlUU")]);
+        assert_eq!(levels[0].sokoban.print_level(), "0706111100102100100111154001100301100111111100");
+
+        assert_eq!(levels[1].title, Some(String::from("2")));
+        assert_eq!(levels[1].author, None);
+        assert_eq!(levels[1].sokoban.boxes().len(), 3);
+    }
+
+    #[test]
+    fn test_read_numeric_collection() {
+        let levels = read_collection("levels/microban_num.soko").unwrap();
+        assert_eq!(levels.len(), 154);
+        assert_eq!(levels[0].as_ref().unwrap().name(), "0706111100102100100111154001100301100111111100");
+    }
+
+    #[test]
+    fn test_parse_collection_error() {
+        // the level that can't be read is kept as its error, the ones around it are still read
+        let collection = "1\n#####\n#@$.#\n#####\n\n2\n#####\n#@$ #\n#####\nAuthor: nobody\n\n3\n#####\n#@$.#\n#####";
+        let levels = parse_collection(collection);
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[0].as_ref().unwrap().title, Some(String::from("1")));
+        match &levels[1] {
+            Err(CollectionError::Level { line, title, error }) => {
+                assert_eq!(*line, 7);
+                assert_eq!(*title, Some(String::from("2")));
+                assert_eq!(*error, LevelError::BoxGoalCountMismatch { boxes: 1, goals: 0 });
+            }
+            _ => panic!("expected a level error"),
+        }
+        let last = levels[2].as_ref().unwrap();
+        assert_eq!(last.title, Some(String::from("3")));
+        assert_eq!(last.author, None);
+    }
+}
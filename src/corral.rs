@@ -0,0 +1,308 @@
+use crate::node::{Node, NodeType, Position};
+use crate::sokoban::{Direction, Sokoban};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::Mutex;
+
+// States the search for a way into a corral goes through before giving up on it
+const CORRAL_SEARCH_LIMIT: usize = 1000;
+// Corrals to remember whether they can be opened, starting over once there are more
+const CORRAL_CACHE_SIZE: usize = 100_000;
+
+/*
+ * An area of floor the player can't get to, and the boxes around it.
+ *
+ *   #######
+ *   #  $ .#
+ *   # @$. #  the goals are in a corral, fenced by the three boxes
+ *   #  $ .#
+ *   #######
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Corral {
+    pub area: Vec<Position>,
+    // indexes of the boxes next to the area
+    pub boxes: Vec<usize>,
+}
+
+#[derive(Default)]
+struct CorralAnswers {
+    can_open: HashMap<Vec<u32>, bool>,
+    // bytes taken by the keys, counted as they come in
+    key_memory: usize,
+}
+
+/*
+ * Whether corrals can be opened, by the world they are on, their boxes and the player zone.
+ * Kept by the solver and shared by the threads of a parallel search like the states table,
+ * starting over once there are more than CORRAL_CACHE_SIZE.
+ */
+#[derive(Default)]
+pub struct CorralCache {
+    answers: Mutex<CorralAnswers>,
+}
+
+impl CorralCache {
+    fn get(&self, key: &[u32]) -> Option<bool> {
+        self.answers.lock().unwrap().can_open.get(key).copied()
+    }
+
+    fn insert(&self, key: Vec<u32>, can_open: bool) {
+        let mut answers = self.answers.lock().unwrap();
+        if answers.can_open.len() >= CORRAL_CACHE_SIZE {
+            answers.can_open.clear();
+            answers.key_memory = 0;
+        }
+        answers.key_memory += key.capacity() * mem::size_of::<u32>();
+        answers.can_open.insert(key, can_open);
+    }
+
+    // Bytes taken by the corrals kept and their keys
+    pub fn memory(&self) -> usize {
+        let answers = self.answers.lock().unwrap();
+        answers.can_open.capacity() * mem::size_of::<(Vec<u32>, bool)>() + answers.key_memory
+    }
+}
+
+impl Sokoban {
+    // Every corral of the current state, boxes in between two areas belong to both
+    pub fn corrals(&mut self) -> Vec<Corral> {
+        let mut seen = HashSet::new();
+        let mut corrals = Vec::new();
+        for start in self.map.keys().collect::<Vec<Position>>() {
+            if !self.is_corral_floor(&start) || !seen.insert(start) {
+                continue;
+            }
+
+            let mut area = vec![start];
+            let mut boxes = Vec::new();
+            let mut queue = vec![start];
+            while let Some(current) = queue.pop() {
+                for adjacent in Node::loop_positions(&current).iter() {
+                    if self.has_box(adjacent) {
+                        let box_index = self.boxes().iter().position(|sbox| sbox == adjacent).unwrap();
+                        if !boxes.contains(&box_index) {
+                            boxes.push(box_index);
+                        }
+                    } else if self.is_corral_floor(adjacent) && seen.insert(*adjacent) {
+                        area.push(*adjacent);
+                        queue.push(*adjacent);
+                    }
+                }
+            }
+            boxes.sort_unstable();
+            corrals.push(Corral { area, boxes });
+        }
+        corrals
+    }
+
+    fn is_corral_floor(&mut self, position: &Position) -> bool {
+        self.get_ntype(position) != NodeType::Wall && !self.has_box(position) && !self.can_reach(position)
+    }
+
+    /*
+     * Whether a corral next to the given box can't ever be opened.
+     *
+     * A corral with boxes out of their goals has to be opened at some point, the player
+     * needs to get inside or the boxes need to get to goals. This is searched for with only
+     * the boxes of the corral, other boxes can only get in the way, so if even then there is
+     * no way, there is none with them either.
+     */
+    pub fn is_corral_deadlock(&mut self, box_index: usize, cache: &CorralCache) -> bool {
+        let sbox = self.boxes()[box_index];
+        if !Node::loop_positions(&sbox).iter().any(|adjacent| self.is_corral_floor(adjacent)) {
+            return false;
+        }
+
+        for corral in self.corrals().iter().filter(|corral| corral.boxes.contains(&box_index)) {
+            if corral.boxes.iter().all(|index| self.goals.contains(&self.boxes()[*index])) {
+                continue;
+            }
+            if !self.can_open_corral(corral, cache) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /*
+     * Searched on the same world with the other boxes taken out, putting them back after.
+     * Answers are kept by the corral boxes and player zone, the same corral shows up again
+     * and again while other boxes move.
+     */
+    fn can_open_corral(&mut self, corral: &Corral, cache: &CorralCache) -> bool {
+        let mut key: Vec<u32> = corral.boxes.iter().map(|index| self.position_index(&self.boxes()[*index]) as u32).collect();
+        key.sort_unstable();
+        let zone = self.player_zone();
+        key.push(self.position_index(&zone) as u32);
+        // pushing and pulling the same boxes don't open the same corrals
+        key.push(self.reversed as u32);
+        if let Some(can_open) = cache.get(&key) {
+            return can_open;
+        }
+
+        let (boxes, player) = (self.boxes().to_vec(), self.player);
+        self.set_boxes(corral.boxes.iter().map(|index| boxes[*index]).collect());
+        let can_open = self.search_corral_opening(corral);
+        self.set_boxes(boxes);
+        self.player = player;
+
+        cache.insert(key, can_open);
+        can_open
+    }
+
+    fn search_corral_opening(&mut self, corral: &Corral) -> bool {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((self.boxes().to_vec(), self.player.unwrap()));
+        while let Some((boxes, player)) = queue.pop_front() {
+            self.set_boxes(boxes);
+            self.player = Some(player);
+            // goals out of the corral don't need to be filled
+            if self.boxes().iter().all(|sbox| self.goals.contains(sbox))
+                || corral.area.iter().any(|position| self.can_reach(position))
+            {
+                return true;
+            }
+            if seen.len() >= CORRAL_SEARCH_LIMIT {
+                return true;
+            }
+            if !seen.insert(self.packed_state()) {
+                continue;
+            }
+
+            for box_index in 0..self.boxes().len() {
+                for direction in Direction::ALL.iter() {
+                    if !self.move_box(box_index, direction) {
+                        continue;
+                    }
+                    if !self.is_deadlock(box_index) {
+                        queue.push_back((self.boxes().to_vec(), self.player.unwrap()));
+                    }
+                    self.undo_move_box(box_index, direction);
+                    self.player = Some(player);
+                }
+            }
+        }
+        false
+    }
+
+    /*
+     * A corral whose boxes can only be pushed into it, with the player able to do every
+     * one of those pushes right now (a PI-corral), and the pushes themselves.
+     *
+     * Such a corral needs one of these pushes sooner or later, pushing other boxes first
+     * can't make any of them possible, so they are the only moves worth trying. Boxes out
+     * of the corral are taken as gone when looking for other pushes, they may move away.
+     * Only for pushing worlds, with the smallest set of pushes when there are many.
+     */
+    pub fn pi_corral(&mut self) -> Option<(Corral, Vec<(usize, Direction)>)> {
+        if self.reversed {
+            return None;
+        }
+
+        let mut best: Option<(Corral, Vec<(usize, Direction)>)> = None;
+        for corral in self.corrals() {
+            if corral.boxes.iter().all(|index| self.goals.contains(&self.boxes()[*index]))
+                && corral.area.iter().all(|position| !self.goals.contains(position))
+            {
+                continue;
+            }
+            let pushes = match self.corral_pushes(&corral) {
+                Some(pushes) => pushes,
+                None => continue,
+            };
+            if best.as_ref().is_none_or(|(_, best)| pushes.len() < best.len()) {
+                best = Some((corral, pushes));
+            }
+        }
+        best
+    }
+
+    // The pushes into the corral if it is a PI-corral
+    fn corral_pushes(&mut self, corral: &Corral) -> Option<Vec<(usize, Direction)>> {
+        let fence: Vec<Position> = corral.boxes.iter().map(|index| self.boxes()[*index]).collect();
+        let blocks = |sokoban: &Sokoban, position: &Position| {
+            sokoban.get_ntype(position) == NodeType::Wall || fence.contains(position)
+        };
+
+        let mut pushes = Vec::new();
+        for (box_index, sbox) in corral.boxes.iter().zip(fence.iter()) {
+            for direction in Direction::ALL.iter() {
+                let (box_future, player_side) = match (direction.step(sbox), direction.opposite().step(sbox)) {
+                    (Some(box_future), Some(player_side)) => (box_future, player_side),
+                    _ => continue,
+                };
+                if blocks(self, &box_future) || blocks(self, &player_side) || corral.area.contains(&player_side) {
+                    continue;
+                }
+                if self.is_dead_square(&box_future) {
+                    continue;
+                }
+                // pushing out of the corral, or a push the player can't do yet
+                if !corral.area.contains(&box_future) || !self.can_reach(&player_side) {
+                    return None;
+                }
+                pushes.push((*box_index, *direction));
+            }
+        }
+        Some(pushes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrals() {
+        let mut sokoban = Sokoban::from_xsb("#######\n#  $ .#\n# @$. #\n#  $ .#\n#######").unwrap();
+        let corrals = sokoban.corrals();
+        assert_eq!(corrals.len(), 1);
+        assert_eq!(corrals[0].area.len(), 6);
+        assert_eq!(corrals[0].boxes, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_pi_corral() {
+        let mut sokoban = Sokoban::from_xsb("#######\n#  $ .#\n# @$. #\n#  $ .#\n#######").unwrap();
+        let (corral, pushes) = sokoban.pi_corral().unwrap();
+        assert_eq!(corral.boxes, vec![0, 1, 2]);
+        assert_eq!(
+            pushes,
+            vec![(0, Direction::Right), (1, Direction::Right), (2, Direction::Right)]
+        );
+
+        // a box out of the corral keeps the player from pushing the middle one, not a PI-corral
+        let mut sokoban = Sokoban::from_xsb("#######\n#. $ .#\n#@$$ .#\n#  $ .#\n#######").unwrap();
+        assert_eq!(sokoban.corrals().len(), 1);
+        assert_eq!(sokoban.pi_corral(), None);
+    }
+
+    #[test]
+    fn test_corral_deadlock() {
+        // the box fencing the goal in can be pushed onto it
+        let cache = CorralCache::default();
+        let mut sokoban = Sokoban::from_xsb("#######\n#@ $. #\n## ####\n#  $  #\n# .   #\n#######").unwrap();
+        assert!(!sokoban.is_corral_deadlock(0, &cache));
+
+        // two boxes side by side against the corral, only the outer one can move
+        let mut sokoban = Sokoban::from_xsb("#######\n#.   ##\n#.@$$ #\n#######").unwrap();
+        assert!(sokoban.is_corral_deadlock(1, &cache));
+    }
+
+    #[test]
+    fn test_corral_cache() {
+        let cache = CorralCache::default();
+        assert_eq!(cache.memory(), 0);
+        let mut sokoban = Sokoban::from_xsb("#######\n#.   ##\n#.@$$ #\n#######").unwrap();
+        assert!(sokoban.is_corral_deadlock(1, &cache));
+        assert_eq!(cache.answers.lock().unwrap().can_open.len(), 1);
+        assert!(cache.memory() > 0);
+
+        // kept on the cache, not on the world, a copy of it finds the same answer there
+        let mut copy = sokoban.clone();
+        assert!(copy.is_corral_deadlock(1, &cache));
+        assert_eq!(cache.answers.lock().unwrap().can_open.len(), 1);
+    }
+}
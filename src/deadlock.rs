@@ -0,0 +1,179 @@
+use crate::grid::BitSet;
+use crate::node::{NodeType, Position};
+use crate::sokoban::{Direction, Sokoban};
+
+impl Sokoban {
+    /*
+     * Squares a box can never be pushed out of into a goal, like a corner without a goal.
+     *
+     * Pulling a box from every goal, with the player standing behind it, gets to every square
+     * a box can be pushed from into that goal. Other boxes are left out, they could move out
+     * of the way, so any floor square never reached this way is dead. On reversed worlds it
+     * is the other way around, boxes are pushed from every goal to find where they can be
+     * pulled from.
+     */
+    pub(crate) fn find_dead_squares(&self) -> BitSet {
+        let mut live = BitSet::new(self.width * self.height);
+        let mut queue: Vec<Position> = Vec::new();
+        for goal in self.goals.iter() {
+            let index = self.map.index(goal).unwrap();
+            if !live.contains(index) {
+                live.insert(index);
+                queue.push(*goal);
+            }
+        }
+
+        while let Some(sbox) = queue.pop() {
+            for direction in Direction::ALL.iter() {
+                let box_future = match direction.step(&sbox) {
+                    Some(box_future) => box_future,
+                    None => continue,
+                };
+                let player = if self.reversed {
+                    direction.opposite().step(&sbox)
+                } else {
+                    direction.step(&box_future)
+                };
+                if self.is_wall(&box_future) || player.is_none_or(|player| self.is_wall(&player)) {
+                    continue;
+                }
+
+                let index = self.map.index(&box_future).unwrap();
+                if !live.contains(index) {
+                    live.insert(index);
+                    queue.push(box_future);
+                }
+            }
+        }
+
+        let mut dead = BitSet::new(self.width * self.height);
+        for (position, ntype) in self.map.iter() {
+            let index = self.map.index(&position).unwrap();
+            if ntype != NodeType::Wall && !live.contains(index) {
+                dead.insert(index);
+            }
+        }
+        dead
+    }
+
+    // Whether a box on this position can't ever get to a goal, no matter where the others are
+    pub fn is_dead_square(&self, position: &Position) -> bool {
+        self.map.index(position).is_some_and(|index| self.dead_squares.contains(index))
+    }
+
+    pub(crate) fn is_wall(&self, position: &Position) -> bool {
+        self.map.get(position).is_none_or(|ntype| ntype == NodeType::Wall)
+    }
+
+    // Whether the state can't be solved anymore after moving the given box
+    pub fn is_deadlock(&self, box_index: usize) -> bool {
+        self.is_dead_square(&self.boxes()[box_index]) || self.is_freeze_deadlock(box_index)
+    }
+
+    /*
+     * Whether moving the given box left boxes frozen out of their goals.
+     *
+     * A box is frozen when it can't move along either axis, an axis is blocked when the box
+     * can't go either way on it, because of walls, frozen boxes or dead squares. Only the box
+     * that moved can have frozen new boxes, since a box that can still move blocks no one.
+     */
+    pub fn is_freeze_deadlock(&self, box_index: usize) -> bool {
+        let mut checking = Vec::new();
+        if !self.is_frozen(&self.boxes()[box_index], &mut checking) {
+            return false;
+        }
+        self.boxes()
+            .iter()
+            .any(|sbox| !self.goals.contains(sbox) && self.is_frozen(sbox, &mut checking))
+    }
+
+    // checking holds the boxes being checked further up, they are taken as walls
+    fn is_frozen(&self, sbox: &Position, checking: &mut Vec<Position>) -> bool {
+        checking.push(*sbox);
+        let frozen = self.is_axis_blocked(sbox, Direction::Left, checking)
+            && self.is_axis_blocked(sbox, Direction::Up, checking);
+        checking.pop();
+        frozen
+    }
+
+    fn is_axis_blocked(&self, sbox: &Position, direction: Direction, checking: &mut Vec<Position>) -> bool {
+        [direction, direction.opposite()].iter().all(|direction| {
+            let box_future = match direction.step(sbox) {
+                Some(box_future) => box_future,
+                None => return true,
+            };
+            // pushing needs the player behind the box, pulling needs it past where the box goes
+            let player = if self.reversed {
+                direction.step(&box_future)
+            } else {
+                direction.opposite().step(sbox)
+            };
+            let player = match player {
+                Some(player) => player,
+                None => return true,
+            };
+            self.is_dead_square(&box_future)
+                || self.is_blocking(&box_future, checking)
+                || self.is_blocking(&player, checking)
+        })
+    }
+
+    fn is_blocking(&self, position: &Position, checking: &mut Vec<Position>) -> bool {
+        if self.is_wall(position) || checking.contains(position) {
+            return true;
+        }
+        self.has_box(position) && self.is_frozen(position, checking)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_squares() {
+        let sokoban = Sokoban::from_xsb("######\n#    #\n# $@.#\n######").unwrap();
+        let dead: Vec<Position> = sokoban.map.keys().filter(|position| sokoban.is_dead_square(position)).collect();
+        assert_eq!(
+            dead,
+            vec![
+                Position::new(1, 1),
+                Position::new(2, 1),
+                Position::new(3, 1),
+                Position::new(4, 1),
+                Position::new(1, 2),
+            ]
+        );
+        assert!(!sokoban.is_dead_square(&Position::new(2, 2)));
+        assert!(!sokoban.is_dead_square(&Position::new(0, 0)));
+    }
+
+    #[test]
+    fn test_dead_squares_when_pulling() {
+        // a box in the corner can be pulled out of it, but not from against the top wall
+        let sokoban = Sokoban::from_xsb("######\n#    #\n# $@.#\n######").unwrap().reverse();
+        assert!(sokoban.reversed);
+        assert!(!sokoban.is_dead_square(&Position::new(1, 2)));
+        assert!(sokoban.is_dead_square(&Position::new(1, 1)));
+    }
+
+    #[test]
+    fn test_freeze_deadlock() {
+        // two boxes against the top wall, off their goals
+        let sokoban = Sokoban::from_xsb("######\n#$$  #\n#  @ #\n#..  #\n######").unwrap();
+        assert!(sokoban.is_freeze_deadlock(1));
+
+        // the same boxes with room to move down
+        let sokoban = Sokoban::from_xsb("######\n#    #\n# $$ #\n# .@.#\n######").unwrap();
+        assert!(!sokoban.is_freeze_deadlock(0));
+
+        // a frozen box on its goal is fine
+        let sokoban = Sokoban::from_xsb("#####\n#*  #\n# @ #\n#####").unwrap();
+        assert!(!sokoban.is_freeze_deadlock(0));
+
+        // pulling, a box in a corner can still be pulled away from it
+        let sokoban = Sokoban::from_xsb("######\n#    #\n# $@ #\n#   .#\n######").unwrap().reverse();
+        assert_eq!(sokoban.boxes(), vec![Position::new(4, 3)]);
+        assert!(!sokoban.is_freeze_deadlock(0));
+    }
+}
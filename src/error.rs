@@ -0,0 +1,47 @@
+use crate::validate::Problem;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LevelError {
+    InvalidHeader(String),
+    TruncatedLevel { expected: usize, found: usize },
+    InvalidTile { x: usize, y: usize, ch: char },
+    NoPlayer,
+    MultiplePlayers(usize),
+    BoxGoalCountMismatch { boxes: usize, goals: usize },
+    Invalid(Vec<Problem>),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelError::InvalidHeader(header) => {
+                write!(f, "level size '{}' is not a valid HHWW header", header)
+            }
+            LevelError::TruncatedLevel { expected, found } => write!(
+                f,
+                "level is truncated, expected {} tiles but found {}",
+                expected, found
+            ),
+            LevelError::InvalidTile { x, y, ch } => {
+                write!(f, "invalid tile '{}' at ({}, {})", ch, x, y)
+            }
+            LevelError::NoPlayer => write!(f, "level has no player"),
+            LevelError::MultiplePlayers(players) => {
+                write!(f, "level has {} players, only one is allowed", players)
+            }
+            LevelError::BoxGoalCountMismatch { boxes, goals } => write!(
+                f,
+                "level has {} boxes but {} goals, they should be the same",
+                boxes, goals
+            ),
+            LevelError::Invalid(problems) => {
+                let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                write!(f, "level can't be played, {}", problems.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for LevelError {}
@@ -0,0 +1,116 @@
+use crate::node::{NodeType, Position};
+
+/*
+ * The tiles that never change on a level, walls, goals and floor, one after the other row
+ * by row so every lookup is an index. Positions outside of the level are walls.
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Grid {
+    width: usize,
+    height: usize,
+    tiles: Vec<NodeType>,
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Grid {
+        Grid {
+            width,
+            height,
+            tiles: vec![NodeType::Empty; width * height],
+        }
+    }
+
+    pub fn index(&self, position: &Position) -> Option<usize> {
+        if position.x < self.width && position.y < self.height {
+            Some(position.y * self.width + position.x)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, position: &Position) -> Option<NodeType> {
+        self.index(position).map(|index| self.tiles[index])
+    }
+
+    pub fn insert(&mut self, position: Position, ntype: NodeType) {
+        let index = self.index(&position).expect("position outside of the grid");
+        self.tiles[index] = ntype;
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    // Every position of the grid, row by row
+    pub fn keys(&self) -> impl Iterator<Item = Position> + '_ {
+        (0..self.height).flat_map(move |y| (0..self.width).map(move |x| Position { x, y }))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Position, NodeType)> + '_ {
+        self.keys().zip(self.tiles.iter().copied())
+    }
+}
+
+/*
+ * One bit per position of a grid, used to know in constant time whether a box is on a
+ * position.
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new(len: usize) -> BitSet {
+        BitSet {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|word| *word = 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid() {
+        let mut grid = Grid::new(3, 2);
+        grid.insert(Position::new(2, 1), NodeType::Wall);
+        assert_eq!(grid.get(&Position::new(2, 1)), Some(NodeType::Wall));
+        assert_eq!(grid.get(&Position::new(1, 1)), Some(NodeType::Empty));
+        assert_eq!(grid.get(&Position::new(3, 0)), None);
+        assert_eq!(grid.keys().nth(4), Some(Position::new(1, 1)));
+        assert_eq!(grid.iter().filter(|(_, ntype)| *ntype == NodeType::Wall).count(), 1);
+    }
+
+    #[test]
+    fn test_bit_set() {
+        let mut set = BitSet::new(130);
+        set.insert(0);
+        set.insert(129);
+        assert!(set.contains(0) && set.contains(129));
+        assert!(!set.contains(64));
+        set.remove(129);
+        assert!(!set.contains(129));
+    }
+}
@@ -1,5 +1,18 @@
-#![feature(generators, generator_trait, vec_remove_item)]
-
-mod node;
-mod sokoban;
+pub mod bidirectional;
+pub mod collection;
+pub mod corral;
+pub mod deadlock;
+pub mod error;
+pub mod grid;
+pub mod limits;
+pub mod macros;
+pub mod matching;
+pub mod node;
+pub mod optimal;
+pub mod packing;
+pub mod parallel;
+pub mod sokoban;
+pub mod solution;
 pub mod solver;
+pub mod table;
+pub mod validate;
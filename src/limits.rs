@@ -0,0 +1,244 @@
+use crate::solution::Solution;
+use crate::solver::Solver;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Limit checks between two looks at the clock and the memory, nodes are counted every time
+const CHECK_INTERVAL: usize = 256;
+
+// How far a search can go, no limit on what is None
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SearchLimits {
+    pub time: Option<Duration>,
+    pub nodes: Option<usize>,
+    // bytes taken by the states the search keeps
+    pub memory: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LimitReason {
+    Time,
+    Nodes,
+    Memory,
+    // the DFS cut branches deeper than its stack holds, and found nothing on the rest
+    Depth,
+}
+
+impl fmt::Display for LimitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            LimitReason::Time => "time",
+            LimitReason::Nodes => "nodes",
+            LimitReason::Memory => "memory",
+            LimitReason::Depth => "depth",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+// What a search went through until it ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SearchStats {
+    pub nodes: usize,
+    pub elapsed: Duration,
+    pub memory: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SolveOutcome {
+    Solved(Solution),
+    // the whole search was gone through without finding a solution
+    Unsolvable,
+    // the search was stopped before it could tell
+    LimitReached { reason: LimitReason, stats: SearchStats },
+}
+
+// The limits of a solver and how close its search is to them
+#[derive(Clone, Debug)]
+pub(crate) struct LimitTracker {
+    pub(crate) limits: SearchLimits,
+    started: Instant,
+    first_node: usize,
+    checks: usize,
+    pub(crate) memory: usize,
+    pub(crate) reason: Option<LimitReason>,
+    pub(crate) depth_cut: bool,
+}
+
+impl LimitTracker {
+    pub(crate) fn new(limits: SearchLimits) -> LimitTracker {
+        LimitTracker {
+            limits,
+            started: Instant::now(),
+            first_node: 0,
+            checks: 0,
+            memory: 0,
+            reason: None,
+            depth_cut: false,
+        }
+    }
+
+    // Starts counting from the given node count and from now on
+    fn restart(&mut self, counter: usize) {
+        *self = LimitTracker {
+            first_node: counter,
+            ..LimitTracker::new(self.limits)
+        };
+    }
+
+    /*
+     * A tracker for another thread of the same search, with the same clock and memory but
+     * its own nodes, the node limit split evenly between the threads.
+     */
+    pub(crate) fn split(&self, threads: usize) -> LimitTracker {
+        let mut limits = self.limits;
+        limits.nodes = limits.nodes.map(|nodes| nodes.div_ceil(threads.max(1)));
+        LimitTracker {
+            limits,
+            first_node: 0,
+            checks: 0,
+            ..self.clone()
+        }
+    }
+}
+
+impl Solver {
+    pub fn set_limits(&mut self, limits: SearchLimits) {
+        self.limits = LimitTracker::new(limits);
+    }
+
+    /*
+     * Runs the given search within the limits, telling apart a level without solution from
+     * a search that was stopped, or had to leave branches out, before it was done.
+     *
+     *   let outcome = solver.solve_within(Solver::solve_push_optimal);
+     */
+    pub fn solve_within<F>(&mut self, search: F) -> SolveOutcome
+    where
+        F: FnOnce(&mut Solver) -> Option<Solution>,
+    {
+        self.limits.restart(self.counter);
+        self.stop.store(false, Ordering::Relaxed);
+        let solution = search(self);
+        let depth_reason = Some(LimitReason::Depth).filter(|_| self.limits.depth_cut);
+        match (solution, self.limits.reason.or(depth_reason)) {
+            (Some(solution), _) => SolveOutcome::Solved(solution),
+            (None, Some(reason)) => SolveOutcome::LimitReached {
+                reason,
+                stats: self.search_stats(),
+            },
+            (None, None) => SolveOutcome::Unsolvable,
+        }
+    }
+
+    pub fn search_stats(&self) -> SearchStats {
+        SearchStats {
+            nodes: self.counter - self.limits.first_node,
+            elapsed: self.limits.started.elapsed(),
+            memory: self.limits.memory,
+        }
+    }
+
+    /*
+     * Whether the search has to stop, with search_memory the bytes it keeps besides the
+     * states table. Once a limit is reached the stop flag is set, so every thread of the
+     * search and every level of the DFS ends.
+     */
+    pub(crate) fn limit_reached(&mut self, search_memory: usize) -> bool {
+        let limits = self.limits.limits;
+        if self.limits.reason.is_some() {
+            return true;
+        }
+        if limits == SearchLimits::default() {
+            return false;
+        }
+
+        let nodes = self.counter - self.limits.first_node;
+        if limits.nodes.is_some_and(|limit| nodes >= limit) {
+            self.limits.reason = Some(LimitReason::Nodes);
+        }
+        self.limits.checks += 1;
+        if self.limits.checks % CHECK_INTERVAL == 1 {
+            if limits.time.is_some_and(|limit| self.limits.started.elapsed() >= limit) {
+                self.limits.reason = Some(LimitReason::Time);
+            }
+            self.limits.memory = self.state_table.memory() + self.corral_cache.memory() + search_memory;
+            if limits.memory.is_some_and(|limit| self.limits.memory >= limit) {
+                self.limits.reason = Some(LimitReason::Memory);
+            }
+        }
+
+        if self.limits.reason.is_some() {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+        self.limits.reason.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &str = "0612111111011111100001110001103300000141103012220001100011111111111110000000";
+
+    #[test]
+    fn test_node_limit() {
+        let mut solver = Solver::new(String::from(LEVEL)).unwrap();
+        solver.set_limits(SearchLimits {
+            nodes: Some(3),
+            ..SearchLimits::default()
+        });
+        match solver.solve_within(Solver::solve_push_optimal) {
+            SolveOutcome::LimitReached { reason, stats } => {
+                assert_eq!(reason, LimitReason::Nodes);
+                assert_eq!(stats.nodes, 3);
+            }
+            outcome => panic!("expected the node limit, got {:?}", outcome),
+        }
+
+        // the DFS stops the same way
+        match solver.solve_within(Solver::solve_sokoban) {
+            SolveOutcome::LimitReached { reason, .. } => assert_eq!(reason, LimitReason::Nodes),
+            outcome => panic!("expected the node limit, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_time_and_memory_limits() {
+        let mut solver = Solver::new(String::from(LEVEL)).unwrap();
+        solver.set_limits(SearchLimits {
+            time: Some(Duration::ZERO),
+            ..SearchLimits::default()
+        });
+        let outcome = solver.solve_within(Solver::solve_bidirectional);
+        assert!(matches!(outcome, SolveOutcome::LimitReached { reason: LimitReason::Time, .. }));
+
+        // the table is empty on the first look, a level with enough states to look again
+        let mut solver = Solver::new(String::from("070801111111010000010102320111034301100232011000000111111111")).unwrap();
+        solver.set_limits(SearchLimits {
+            memory: Some(1),
+            ..SearchLimits::default()
+        });
+        let outcome = solver.solve_within(|solver| solver.solve_parallel(2));
+        assert!(matches!(outcome, SolveOutcome::LimitReached { reason: LimitReason::Memory, .. }));
+    }
+
+    #[test]
+    fn test_solved_within_limits() {
+        let mut solver = Solver::new(String::from(LEVEL)).unwrap();
+        solver.set_limits(SearchLimits {
+            time: Some(Duration::from_secs(60)),
+            nodes: Some(1_000_000),
+            memory: None,
+        });
+        assert!(matches!(solver.solve_within(Solver::solve_ida), SolveOutcome::Solved(_)));
+    }
+
+    #[test]
+    fn test_unsolvable() {
+        // the box can never get out of the corner
+        let mut solver = Solver::from_sokoban(crate::sokoban::Sokoban::from_xsb("#####\n#$ .#\n#  @#\n#####").unwrap()).unwrap();
+        assert_eq!(solver.solve_within(Solver::solve_push_optimal), SolveOutcome::Unsolvable);
+    }
+}
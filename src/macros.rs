@@ -0,0 +1,179 @@
+use crate::node::{Node, Position};
+use crate::sokoban::{Direction, Sokoban};
+use std::collections::{HashSet, VecDeque};
+
+/*
+ * An area holding every goal and no box, with a single square leading into it.
+ *
+ *   #######
+ *   #..e  #
+ *   #..## #  the goal room is on the left, entered through e
+ *   ##### #
+ *   # @$$ #
+ *   #  $$ #
+ *   #######
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GoalRoom {
+    pub area: Vec<Position>,
+    pub entrance: Position,
+}
+
+impl Sokoban {
+    /*
+     * Whether a box that moved along direction onto the given position is in a one-wide
+     * corridor, with walls on both sides of it and of the player next to it. Leaving it
+     * there only keeps the player from getting past it, so the box is taken all the way
+     * through instead. Goals stop it, a box may need to stay.
+     */
+    pub fn is_tunnel(&self, position: &Position, direction: &Direction) -> bool {
+        let sides = match direction {
+            Direction::Up | Direction::Down => [Direction::Left, Direction::Right],
+            Direction::Left | Direction::Right => [Direction::Up, Direction::Down],
+        };
+        // ahead of the box when pulling, behind it when pushing
+        let player = match self.reversed {
+            true => direction.step(position),
+            false => direction.opposite().step(position),
+        };
+        let is_walled = |square: &Position| {
+            sides
+                .iter()
+                .all(|side| side.step(square).is_none_or(|side| self.is_wall(&side)))
+        };
+        !self.goals.contains(position)
+            && is_walled(position)
+            && player.is_some_and(|player| is_walled(&player))
+    }
+
+    /*
+     * The smallest goal room of the level, if there is one. Every square is tried as the
+     * entrance, taking it out and looking for the area left with all the goals in it.
+     */
+    pub fn goal_room(&self) -> Option<GoalRoom> {
+        let mut room: Option<GoalRoom> = None;
+        for entrance in self.map.keys() {
+            if self.is_wall(&entrance) || self.goals.contains(&entrance) {
+                continue;
+            }
+
+            let area = match self.goals.first() {
+                Some(goal) => self.area_without(goal, &entrance),
+                None => return None,
+            };
+            let is_room = self.goals.len() > 1
+                && self.goals.iter().all(|goal| area.contains(goal))
+                && self.boxes().iter().all(|sbox| !area.contains(sbox))
+                && Node::loop_positions(&entrance)
+                    .iter()
+                    .any(|outside| !self.is_wall(outside) && !area.contains(outside));
+            if is_room && room.as_ref().is_none_or(|room| area.len() < room.area.len()) {
+                let mut area: Vec<Position> = area.into_iter().collect();
+                area.sort_unstable();
+                room = Some(GoalRoom { area, entrance });
+            }
+        }
+        room
+    }
+
+    // Floor connected to start without going through the excluded square, with only walls
+    fn area_without(&self, start: &Position, excluded: &Position) -> HashSet<Position> {
+        let mut area = HashSet::new();
+        let mut queue = vec![*start];
+        area.insert(*start);
+        while let Some(current) = queue.pop() {
+            for adjacent in Node::loop_positions(&current).iter() {
+                if adjacent != excluded && !self.is_wall(adjacent) && area.insert(*adjacent) {
+                    queue.push(*adjacent);
+                }
+            }
+        }
+        area
+    }
+
+    /*
+     * Fewest moves of the given box until it and the player are where is_target wants them,
+     * the other boxes staying where they are, as the directions of every move. The state is
+     * left as it was.
+     */
+    pub fn box_path<F>(&mut self, box_index: usize, is_target: F) -> Option<Vec<Direction>>
+    where
+        F: Fn(&Position, &Position) -> bool,
+    {
+        let (start, player) = (self.boxes()[box_index], self.player.unwrap());
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        let mut found = None;
+        queue.push_back((start, player, Vec::new()));
+        while let Some((sbox, current_player, moves)) = queue.pop_front() {
+            self.set_box(box_index, sbox);
+            self.player = Some(current_player);
+            if is_target(&sbox, &current_player) {
+                found = Some(moves);
+                break;
+            }
+            if !seen.insert((sbox, self.player_zone())) {
+                continue;
+            }
+
+            for direction in Direction::ALL.iter() {
+                if self.move_box(box_index, direction) {
+                    let mut next_moves = moves.clone();
+                    next_moves.push(*direction);
+                    queue.push_back((self.boxes()[box_index], self.player.unwrap(), next_moves));
+                    self.undo_move_box(box_index, direction);
+                    self.player = Some(current_player);
+                }
+            }
+        }
+
+        self.set_box(box_index, start);
+        self.player = Some(player);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel() {
+        let sokoban = Sokoban::from_xsb("#######\n#@$   #\n### ###\n  # #\n  # #\n  #.#\n  ###").unwrap();
+        assert!(sokoban.is_tunnel(&Position::new(3, 3), &Direction::Down));
+        // just in, the player could still walk around it
+        assert!(!sokoban.is_tunnel(&Position::new(3, 2), &Direction::Down));
+        // a goal in the corridor
+        assert!(!sokoban.is_tunnel(&Position::new(3, 5), &Direction::Down));
+
+        // pulling the box back up, the player goes ahead of it
+        let sokoban = sokoban.reverse();
+        assert!(sokoban.is_tunnel(&Position::new(3, 4), &Direction::Up));
+        assert!(sokoban.is_tunnel(&Position::new(3, 3), &Direction::Up));
+        // the player would be out of the corridor, free to walk around the box
+        assert!(!sokoban.is_tunnel(&Position::new(3, 2), &Direction::Up));
+    }
+
+    #[test]
+    fn test_goal_room() {
+        let sokoban =
+            Sokoban::from_xsb("#######\n#..   #\n#..## #\n##### #\n#  $$ #\n# @$$ #\n#     #\n#######").unwrap();
+        let room = sokoban.goal_room().unwrap();
+        assert_eq!(room.entrance, Position::new(3, 1));
+        assert_eq!(room.area.len(), 4);
+
+        // the goals are out in the open
+        let sokoban = Sokoban::from_xsb("######\n#@$. #\n# $. #\n######").unwrap();
+        assert_eq!(sokoban.goal_room(), None);
+    }
+
+    #[test]
+    fn test_box_path() {
+        let mut sokoban = Sokoban::from_xsb("######\n#    #\n#@$  #\n#   .#\n######").unwrap();
+        let path = sokoban.box_path(0, |sbox, _| *sbox == Position::new(4, 3)).unwrap();
+        assert_eq!(path.len(), 3);
+        assert_eq!(path.iter().filter(|direction| **direction == Direction::Right).count(), 2);
+        assert_eq!(sokoban.boxes(), vec![Position::new(2, 2)]);
+        assert_eq!(sokoban.player, Some(Position::new(1, 2)));
+    }
+}
@@ -1,23 +1,223 @@
 use log::info;
-use pretty_env_logger;
-use sokoban::solver::Solver;
+use sokoban::collection::{read_collection, CollectionError};
+use sokoban::limits::{SearchLimits, SolveOutcome};
+use sokoban::sokoban::Sokoban;
+use sokoban::solver::{SearchDirection, Solver};
 use std::env;
-use std::time::Instant;
+use std::process;
+use std::time::{Duration, Instant};
 
-fn main() {
-    pretty_env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    let sokoban_level = &args[1];
+const EXIT_USAGE: i32 = 2;
+const EXIT_INVALID_LEVEL: i32 = 3;
+const EXIT_UNREADABLE_FILE: i32 = 4;
+const EXIT_EXPORT_FAILED: i32 = 5;
+
+const USAGE: &str = "usage: sokoban-solver [--optimal pushes|moves | --ida | --bidirectional] [--direction forward|reverse] [--threads <N>] [--table-memory <MiB>] [--time-limit <seconds>] [--node-limit <N>] [--memory-limit <MiB>] <level> | --file <collection>";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mode {
+    // first solution the reverse DFS finds
+    Any,
+    PushOptimal,
+    MoveOptimal,
+    // push optimal as well, iterative deepening over the reverse DFS
+    Iterative,
+    // pushing from the start and pulling from the goals until they meet
+    Bidirectional,
+}
+
+struct Options {
+    level: Option<String>,
+    file: Option<String>,
+    mode: Mode,
+    direction: SearchDirection,
+    // parallel search threads, the DFS runs on the main thread without them
+    threads: Option<usize>,
+    table_memory: Option<usize>,
+    limits: SearchLimits,
+}
 
-    let mut solver = Solver::new(sokoban_level.clone());
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        level: None,
+        file: None,
+        mode: Mode::Any,
+        direction: SearchDirection::Reverse,
+        threads: None,
+        table_memory: None,
+        limits: SearchLimits::default(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => {
+                let path = args.next().ok_or("--file needs the path to a collection")?;
+                options.file = Some(path.clone());
+            }
+            "--optimal" => {
+                options.mode = match args.next().map(|kind| kind.as_str()) {
+                    Some("pushes") => Mode::PushOptimal,
+                    Some("moves") => Mode::MoveOptimal,
+                    _ => return Err(String::from("--optimal needs the value to optimize: pushes or moves")),
+                };
+            }
+            "--ida" => options.mode = Mode::Iterative,
+            "--bidirectional" => options.mode = Mode::Bidirectional,
+            "--direction" => {
+                options.direction = match args.next().map(|direction| direction.as_str()) {
+                    Some("forward") => SearchDirection::Forward,
+                    Some("reverse") => SearchDirection::Reverse,
+                    _ => return Err(String::from("--direction needs the way to search: forward or reverse")),
+                };
+            }
+            "--threads" => {
+                let threads = args.next().and_then(|threads| threads.parse::<usize>().ok());
+                let threads = threads.filter(|threads| *threads > 0);
+                options.threads = Some(threads.ok_or("--threads needs how many threads to search on")?);
+            }
+            "--table-memory" => {
+                let megabytes = args.next().and_then(|megabytes| megabytes.parse::<usize>().ok());
+                let bytes = megabytes.and_then(|megabytes| megabytes.checked_mul(1024 * 1024));
+                options.table_memory = Some(bytes.ok_or("--table-memory needs the MiB the states table can use")?);
+            }
+            "--time-limit" => {
+                // negative, NaN and durations too long to keep are all refused
+                let seconds = args.next().and_then(|seconds| seconds.parse::<f64>().ok());
+                let time = seconds.and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+                options.limits.time = Some(time.ok_or("--time-limit needs the seconds the search can take")?);
+            }
+            "--node-limit" => {
+                let nodes = args.next().and_then(|nodes| nodes.parse::<usize>().ok());
+                options.limits.nodes = Some(nodes.ok_or("--node-limit needs the nodes the search can go through")?);
+            }
+            "--memory-limit" => {
+                let megabytes = args.next().and_then(|megabytes| megabytes.parse::<usize>().ok());
+                let bytes = megabytes.and_then(|megabytes| megabytes.checked_mul(1024 * 1024));
+                options.limits.memory = Some(bytes.ok_or("--memory-limit needs the MiB the search can use")?);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.level = Some(arg.clone()),
+        }
+    }
+
+    if options.level.is_none() == options.file.is_none() {
+        return Err(String::from("give either a level or a --file"));
+    }
+    // the optimal and bidirectional searches have their own way to go
+    let own_direction = matches!(options.mode, Mode::PushOptimal | Mode::MoveOptimal | Mode::Bidirectional);
+    if own_direction && options.direction != SearchDirection::Reverse {
+        return Err(String::from("--direction only works with the DFS and --ida"));
+    }
+    if options.threads.is_some() && options.mode != Mode::Any {
+        return Err(String::from("--threads only works with the DFS"));
+    }
+    Ok(options)
+}
+
+// Solves the level and prints how it went, the exit code to end with if something went wrong
+fn solve(name: &str, sokoban: &Sokoban, options: &Options) -> Result<(), i32> {
+    let mut solver = match Solver::from_sokoban(sokoban.clone()) {
+        Ok(solver) => solver,
+        Err(err) => {
+            eprintln!("Invalid level {}: {}", name, err);
+            println!("{},invalid", name);
+            return Err(EXIT_INVALID_LEVEL);
+        }
+    };
+    if let Some(memory) = options.table_memory {
+        solver.set_table_memory(memory);
+    }
+    if options.direction != SearchDirection::Reverse {
+        solver.set_direction(options.direction);
+    }
+    solver.set_limits(options.limits);
     info!("{}", solver.sokoban);
     let start = Instant::now();
-    let was_solved = solver.solve_sokoban();
-    info!("Was solved? {} - steps: {}", was_solved, solver.counter);
+    let outcome = solver.solve_within(|solver| match options.mode {
+        Mode::Any => match options.threads {
+            Some(threads) => solver.solve_parallel(threads),
+            None => solver.solve_sokoban(),
+        },
+        Mode::PushOptimal => solver.solve_push_optimal(),
+        Mode::MoveOptimal => solver.solve_move_optimal(),
+        Mode::Iterative => solver.solve_ida(),
+        Mode::Bidirectional => solver.solve_bidirectional(),
+    });
+    info!("Was solved? {} - steps: {}", matches!(outcome, SolveOutcome::Solved(_)), solver.counter);
+    info!("States table: {:?}", solver.table_stats());
     info!("Time elapsed solving sokoban is: {:?}", start.elapsed());
-    if was_solved {
-        println!("{},{:?}", sokoban_level, start.elapsed().as_secs_f64());
+    match outcome {
+        SolveOutcome::Solved(solution) => match solution.to_lurd(sokoban) {
+            Some(lurd) => println!("{},{:?},{}", name, start.elapsed().as_secs_f64(), lurd),
+            None => {
+                eprintln!("Could not write the solution of {} in LURD, some step of it can't be replayed", name);
+                println!("{},export-failed", name);
+                return Err(EXIT_EXPORT_FAILED);
+            }
+        },
+        SolveOutcome::Unsolvable => println!("{},unsolvable", name),
+        SolveOutcome::LimitReached { reason, stats } => {
+            println!("{},limit,{},{},{:?}", name, reason, stats.nodes, stats.elapsed.as_secs_f64())
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(EXIT_USAGE);
+    });
+
+    if let Some(path) = &options.file {
+        let levels = read_collection(path).unwrap_or_else(|err| {
+            eprintln!("Could not read {}: {}", path, err);
+            process::exit(EXIT_UNREADABLE_FILE);
+        });
+        // levels that can't be read or are invalid are reported and skipped, the run fails at
+        // the end, and a solution that can't be written fails it before them
+        let (mut invalid, mut export_failed) = (false, false);
+        for level in levels.iter() {
+            let result = match level {
+                Ok(level) => solve(&level.name(), &level.sokoban, &options),
+                Err(err) => {
+                    let name = match err {
+                        CollectionError::Level { title: Some(title), .. } => title.clone(),
+                        CollectionError::Level { line, .. } => format!("line {}", line),
+                        CollectionError::Io(_) => path.clone(),
+                    };
+                    eprintln!("Invalid level {}: {}", name, err);
+                    println!("{},invalid", name);
+                    Err(EXIT_INVALID_LEVEL)
+                }
+            };
+            invalid |= result == Err(EXIT_INVALID_LEVEL);
+            export_failed |= result == Err(EXIT_EXPORT_FAILED);
+        }
+        if export_failed {
+            process::exit(EXIT_EXPORT_FAILED);
+        }
+        if invalid {
+            process::exit(EXIT_INVALID_LEVEL);
+        }
+        return;
+    }
+
+    let sokoban_level = options.level.clone().unwrap();
+    // anything that is not in a numeric format is taken as XSB with '|' between rows
+    let sokoban = if Sokoban::is_numeric(&sokoban_level) {
+        Sokoban::new(&sokoban_level)
     } else {
-        println!("{},notsolved", sokoban_level);
+        Sokoban::from_xsb(&sokoban_level)
+    };
+    let sokoban = sokoban.unwrap_or_else(|err| {
+        eprintln!("Invalid level: {}", err);
+        process::exit(EXIT_INVALID_LEVEL);
+    });
+    if let Err(code) = solve(&sokoban_level, &sokoban, &options) {
+        process::exit(code);
     }
 }
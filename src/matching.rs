@@ -0,0 +1,101 @@
+// Cost given to pairs that can't be matched, higher than any real matching can get to
+const NO_MATCH: i64 = 1 << 40;
+
+/*
+ * Minimum cost perfect matching between the rows and columns of a square cost matrix,
+ * with the Hungarian algorithm, None if some row can't be matched to any free column.
+ *
+ * Used to match every box to its own goal, a box can't fill two goals, so the sum of the
+ * distances of each box to its matched goal is a lower bound of the pushes left, better than
+ * sending every box to its closest goal.
+ */
+pub fn min_cost_matching(costs: &[Vec<Option<usize>>]) -> Option<usize> {
+    let size = costs.len();
+    let cost = |row: usize, column: usize| costs[row - 1][column - 1].map_or(NO_MATCH, |cost| cost as i64);
+
+    // potentials of rows and columns, and the row matched to every column, 1 based with 0 as none
+    let mut row_potential = vec![0_i64; size + 1];
+    let mut column_potential = vec![0_i64; size + 1];
+    let mut matched = vec![0_usize; size + 1];
+    let mut way = vec![0_usize; size + 1];
+    for row in 1..=size {
+        matched[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![i64::MAX; size + 1];
+        let mut used = vec![false; size + 1];
+        loop {
+            used[column] = true;
+            let current_row = matched[column];
+            let mut delta = i64::MAX;
+            let mut next_column = 0;
+            for free_column in 1..=size {
+                if used[free_column] {
+                    continue;
+                }
+                let slack = cost(current_row, free_column) - row_potential[current_row] - column_potential[free_column];
+                if slack < min_slack[free_column] {
+                    min_slack[free_column] = slack;
+                    way[free_column] = column;
+                }
+                if min_slack[free_column] < delta {
+                    delta = min_slack[free_column];
+                    next_column = free_column;
+                }
+            }
+            for other in 0..=size {
+                if used[other] {
+                    row_potential[matched[other]] += delta;
+                    column_potential[other] -= delta;
+                } else {
+                    min_slack[other] -= delta;
+                }
+            }
+            column = next_column;
+            if matched[column] == 0 {
+                break;
+            }
+        }
+
+        // flip the augmenting path found
+        while column != 0 {
+            let previous = way[column];
+            matched[column] = matched[previous];
+            column = previous;
+        }
+    }
+
+    let total: i64 = (1..=size).map(|column| cost(matched[column], column)).sum();
+    if total >= NO_MATCH {
+        None
+    } else {
+        Some(total as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_cost_matching() {
+        assert_eq!(min_cost_matching(&[]), Some(0));
+
+        // both boxes are closest to the first goal, one of them has to go further
+        let costs = vec![vec![Some(1), Some(5)], vec![Some(2), Some(3)]];
+        assert_eq!(min_cost_matching(&costs), Some(4));
+
+        let costs = vec![
+            vec![Some(4), Some(1), Some(3)],
+            vec![Some(2), Some(0), Some(5)],
+            vec![Some(3), Some(2), Some(2)],
+        ];
+        assert_eq!(min_cost_matching(&costs), Some(5));
+    }
+
+    #[test]
+    fn test_no_perfect_matching() {
+        // both boxes can only get to the first goal
+        let costs = vec![vec![Some(1), None], vec![Some(2), None]];
+        assert_eq!(min_cost_matching(&costs), None);
+    }
+}
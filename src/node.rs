@@ -32,19 +32,19 @@ pub enum NodeType {
 
 impl NodeType {
     pub fn is_player(&self) -> bool {
-        [NodeType::Player, NodeType::PlayerOnWhole].contains(self)
+        matches!(self, NodeType::Player | NodeType::PlayerOnWhole)
     }
 
     pub fn is_box(&self) -> bool {
-        [NodeType::Box, NodeType::BoxOnWhole].contains(self)
+        matches!(self, NodeType::Box | NodeType::BoxOnWhole)
     }
 
     pub fn is_whole(&self) -> bool {
-        [NodeType::Whole, NodeType::BoxOnWhole, NodeType::PlayerOnWhole].contains(self)
+        matches!(self, NodeType::Whole | NodeType::BoxOnWhole | NodeType::PlayerOnWhole)
     }
 
     pub fn can_move(&self) -> bool {
-        ![NodeType::Box, NodeType::BoxOnWhole, NodeType::Wall].contains(self)
+        !matches!(self, NodeType::Box | NodeType::BoxOnWhole | NodeType::Wall)
     }
 }
 
@@ -77,6 +77,19 @@ impl NodeType {
         }
     }
 
+    pub fn from_xsb(tile: char) -> Result<NodeType, &'static str> {
+        match tile {
+            ' ' | '-' | '_' => Ok(NodeType::Empty),
+            '#' => Ok(NodeType::Wall),
+            '.' => Ok(NodeType::Whole),
+            '$' => Ok(NodeType::Box),
+            '@' => Ok(NodeType::Player),
+            '*' => Ok(NodeType::BoxOnWhole),
+            '+' => Ok(NodeType::PlayerOnWhole),
+            _ => Err("No match for NodeType"),
+        }
+    }
+
     // Reverse type to play reverse sokoban (pulling instead of pushing)
     pub fn reverse_build(node_type: u32) -> Result<NodeType, &'static str> {
         match node_type {
@@ -173,9 +186,9 @@ mod tests {
         let node2 = Node {ntype: NodeType::Wall, position: Position::new(0, 1) };
         let node3 = Node {ntype: NodeType::Empty, position: Position::new(0, 2) };
 
-        assert_eq!(node1 != node2, true);
-        assert_eq!(node1 < node2, true);
-        assert_eq!(node1 == node3, true);
+        assert!(node1 != node2);
+        assert!(node1 < node2);
+        assert!(node1 == node3);
     }
 
     #[test]
@@ -184,8 +197,8 @@ mod tests {
         let pos2 = Position::new(1, 1);
         let pos3 = Position::new(1, 1);
 
-        assert_eq!(pos1 == pos2, false);
-        assert_eq!(pos2 == pos3, true);
+        assert!(pos1 != pos2);
+        assert!(pos2 == pos3);
     }
 
     #[test]
@@ -193,4 +206,11 @@ mod tests {
         let ntype = NodeType::build(2).unwrap();
         assert_eq!(ntype, NodeType::Whole);
     }
+
+    #[test]
+    fn test_node_type_from_xsb() {
+        assert_eq!(NodeType::from_xsb('-').unwrap(), NodeType::Empty);
+        assert_eq!(NodeType::from_xsb('+').unwrap(), NodeType::PlayerOnWhole);
+        assert!(NodeType::from_xsb('x').is_err());
+    }
 }
@@ -0,0 +1,295 @@
+use crate::matching::min_cost_matching;
+use crate::node::Position;
+use crate::sokoban::Direction;
+use crate::solution::{Pull, Solution};
+use crate::solver::Solver;
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::mem;
+
+// A state reached by the A* search, with the pull that got us there from its parent
+pub(crate) struct SearchNode {
+    pub(crate) boxes: Vec<Position>,
+    pub(crate) player: Position,
+    pub(crate) cost: usize,
+    pub(crate) parent: Option<usize>,
+    pub(crate) pull: Option<Pull>,
+}
+
+// A state of the move optimal search, where the exact player position matters
+struct MoveNode {
+    boxes: Vec<Position>,
+    player: Position,
+    moves: usize,
+    pushes: usize,
+    parent: Option<usize>,
+    step: Option<(Direction, bool)>,
+}
+
+impl Solver {
+    /*
+     * A* search over the pulls of the reverse world, finding a solution with the minimum
+     * number of pushes.
+     *
+     * States are the box positions and the player zone, so two states that only differ on
+     * where the player stands inside the same zone are the same state. The estimate is the
+     * cheapest matching of boxes to goals, every box needs at least that many pulls to get to
+     * a goal of its own, so it never overestimates and the first solved state popped is
+     * optimal.
+     */
+    pub fn solve_push_optimal(&mut self) -> Option<Solution> {
+        let mut nodes: Vec<SearchNode> = Vec::new();
+        let mut open = BinaryHeap::new();
+        let mut best_cost: HashMap<(Vec<Position>, Position), usize> = HashMap::new();
+
+        for player in self.player_zones() {
+            self.sokoban.player = Some(player);
+            let estimate = match self.lower_bound() {
+                Some(estimate) => estimate,
+                None => continue,
+            };
+            nodes.push(SearchNode {
+                boxes: self.sokoban.boxes().to_vec(),
+                player,
+                cost: 0,
+                parent: None,
+                pull: None,
+            });
+            open.push(Reverse((estimate, estimate, nodes.len() - 1)));
+        }
+
+        while let Some(Reverse((_, _, index))) = open.pop() {
+            if self.limit_reached(nodes_memory(nodes.len(), self.sokoban.boxes().len())) {
+                return None;
+            }
+            self.sokoban.set_boxes(nodes[index].boxes.clone());
+            self.sokoban.player = Some(nodes[index].player);
+            let cost = nodes[index].cost;
+
+            let key = self.state_key();
+            if best_cost.get(&key).is_some_and(|best| *best <= cost) {
+                continue;
+            }
+            best_cost.insert(key, cost);
+
+            if self.is_solved() {
+                debug!("push optimal solution found with {} pushes", cost);
+                return Some(Solution::from_pulls(self.original_player, &Solver::trace_pulls(&nodes, index)));
+            }
+
+            self.counter += 1;
+            for box_index in 0..self.sokoban.boxes().len() {
+                for direction in Direction::ALL.iter() {
+                    let box_position = self.sokoban.boxes()[box_index];
+                    if !self.sokoban.move_box(box_index, direction) {
+                        continue;
+                    }
+
+                    let is_deadlock = self.sokoban.is_deadlock(box_index) || self.sokoban.is_corral_deadlock(box_index, &self.corral_cache);
+                    let estimate = if is_deadlock { None } else { self.lower_bound() };
+                    if let Some(estimate) = estimate {
+                        nodes.push(SearchNode {
+                            boxes: self.sokoban.boxes().to_vec(),
+                            player: self.sokoban.player.unwrap(),
+                            cost: cost + 1,
+                            parent: Some(index),
+                            pull: Some(Pull {
+                                box_position,
+                                direction: *direction,
+                            }),
+                        });
+                        open.push(Reverse((cost + 1 + estimate, estimate, nodes.len() - 1)));
+                    }
+                    self.sokoban.undo_move_box(box_index, direction);
+                    self.sokoban.player = Some(nodes[index].player);
+                }
+            }
+        }
+        None
+    }
+
+    /*
+     * A* search over single player moves on the level as given, finding the solution with
+     * the minimum number of moves, and among those the one with the fewest pushes.
+     *
+     * The cost is the pair (moves, pushes) compared in that order, and both get the same
+     * estimate, the cheapest matching of boxes to goals, since every push
+     * is also a move. States keep the exact player position, walking one square is already a
+     * different state.
+     */
+    pub fn solve_move_optimal(&mut self) -> Option<Solution> {
+        let mut sokoban = self.start.clone();
+        let distances: Vec<HashMap<Position, usize>> = sokoban
+            .goals
+            .iter()
+            .map(|goal| Solver::push_distances(&sokoban, *goal, true))
+            .collect();
+        let estimate = |boxes: &[Position]| -> Option<usize> {
+            let costs: Vec<Vec<Option<usize>>> = boxes
+                .iter()
+                .map(|sbox| distances.iter().map(|distance| distance.get(sbox).copied()).collect())
+                .collect();
+            min_cost_matching(&costs)
+        };
+
+        let mut nodes = vec![MoveNode {
+            boxes: sokoban.boxes().to_vec(),
+            player: self.original_player,
+            moves: 0,
+            pushes: 0,
+            parent: None,
+            step: None,
+        }];
+        let mut open = BinaryHeap::new();
+        let mut best_cost: HashMap<(Vec<Position>, Position), (usize, usize)> = HashMap::new();
+        let start_estimate = estimate(sokoban.boxes())?;
+        open.push(Reverse((start_estimate, start_estimate, 0)));
+
+        while let Some(Reverse((_, _, index))) = open.pop() {
+            if self.limit_reached(nodes_memory(nodes.len(), sokoban.boxes().len())) {
+                return None;
+            }
+            let (moves, pushes, player) = (nodes[index].moves, nodes[index].pushes, nodes[index].player);
+            sokoban.set_boxes(nodes[index].boxes.clone());
+            sokoban.player = Some(player);
+
+            let mut key_boxes = sokoban.boxes().to_vec();
+            key_boxes.sort();
+            let key = (key_boxes, player);
+            if best_cost.get(&key).is_some_and(|best| *best <= (moves, pushes)) {
+                continue;
+            }
+            best_cost.insert(key, (moves, pushes));
+
+            if sokoban.is_resolved() {
+                debug!("move optimal solution found with {} moves and {} pushes", moves, pushes);
+                let mut steps = Vec::new();
+                let mut current = index;
+                while let (Some(step), Some(parent)) = (nodes[current].step, nodes[current].parent) {
+                    steps.push(step);
+                    current = parent;
+                }
+                steps.reverse();
+                return Some(Solution::from_moves(self.original_player, &steps));
+            }
+
+            self.counter += 1;
+            for direction in Direction::ALL.iter() {
+                let next = match direction.step(&player) {
+                    Some(next) => next,
+                    None => continue,
+                };
+
+                let mut boxes = sokoban.boxes().to_vec();
+                let is_push = sokoban.get_ntype(&next).is_box();
+                if is_push {
+                    // a box pushed into a dead square can't be solved anymore
+                    let box_future = match direction.step(&next) {
+                        Some(box_future)
+                            if sokoban.get_ntype(&box_future).can_move() && !sokoban.is_dead_square(&box_future) =>
+                        {
+                            box_future
+                        }
+                        _ => continue,
+                    };
+                    let box_index = boxes.iter().position(|sbox| *sbox == next).unwrap();
+                    boxes[box_index] = box_future;
+
+                    sokoban.set_box(box_index, box_future);
+                    sokoban.player = Some(next);
+                    let is_deadlock = sokoban.is_freeze_deadlock(box_index) || sokoban.is_corral_deadlock(box_index, &self.corral_cache);
+                    sokoban.set_box(box_index, next);
+                    sokoban.player = Some(player);
+                    if is_deadlock {
+                        continue;
+                    }
+                } else if !sokoban.get_ntype(&next).can_move() {
+                    continue;
+                }
+
+                let box_estimate = match estimate(&boxes) {
+                    Some(box_estimate) => box_estimate,
+                    None => continue,
+                };
+                let next_pushes = pushes + is_push as usize;
+                nodes.push(MoveNode {
+                    boxes,
+                    player: next,
+                    moves: moves + 1,
+                    pushes: next_pushes,
+                    parent: Some(index),
+                    step: Some((*direction, is_push)),
+                });
+                open.push(Reverse((
+                    moves + 1 + box_estimate,
+                    next_pushes + box_estimate,
+                    nodes.len() - 1,
+                )));
+            }
+        }
+        None
+    }
+
+    // Box positions in order plus the player zone, the same state no matter the box indexes
+    fn state_key(&mut self) -> (Vec<Position>, Position) {
+        let mut boxes = self.sokoban.boxes().to_vec();
+        boxes.sort();
+        (boxes, self.sokoban.player_zone())
+    }
+
+    pub(crate) fn trace_pulls(nodes: &[SearchNode], index: usize) -> Vec<Pull> {
+        let mut pulls = Vec::new();
+        let mut current = Some(index);
+        while let Some(index) = current {
+            if let Some(pull) = nodes[index].pull {
+                pulls.push(pull);
+            }
+            current = nodes[index].parent;
+        }
+        pulls.reverse();
+        pulls
+    }
+}
+
+// Rough bytes taken by the nodes of a search, each with its boxes, its best cost key and its
+// place in the open list
+pub(crate) fn nodes_memory(nodes: usize, boxes: usize) -> usize {
+    nodes * (mem::size_of::<SearchNode>() + 2 * boxes * mem::size_of::<Position>() + 64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sokoban::Sokoban;
+    use crate::solver::Solver;
+
+    #[test]
+    fn test_push_optimal_solution() {
+        let sokoban_level = String::from("0706111100102100100111154001100301100111111100");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_push_optimal().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+        assert_eq!(solution.pushes().len(), 8);
+    }
+
+    #[test]
+    fn test_move_optimal_solution() {
+        let sokoban_level = String::from("0706111100102100100111154001100301100111111100");
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
+        let mut solver = Solver::new(sokoban_level).unwrap();
+        let solution = solver.solve_move_optimal().unwrap();
+        assert!(solution.verify(&sokoban));
+        assert_eq!(solution.to_lurd(&sokoban).unwrap().len(), 33);
+        assert_eq!(solution.pushes().len(), 8);
+    }
+
+    #[test]
+    fn test_push_optimal_is_not_longer_than_dfs() {
+        let sokoban_level = String::from("0706111111100001101401103501102501100001111111");
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
+        let dfs = Solver::new(sokoban_level.clone()).unwrap().solve_sokoban().unwrap();
+        let optimal = Solver::new(sokoban_level).unwrap().solve_push_optimal().unwrap();
+        assert!(optimal.verify(&sokoban));
+        assert!(optimal.pushes().len() <= dfs.pushes().len());
+    }
+}
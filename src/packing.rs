@@ -0,0 +1,95 @@
+use crate::macros::GoalRoom;
+use crate::node::{Node, Position};
+use crate::sokoban::Sokoban;
+use std::collections::HashSet;
+
+impl Sokoban {
+    /*
+     * An order in which the goals of the goal room can be filled, as goal indexes, None if
+     * there is no goal room or no order was found.
+     *
+     * Found backwards, starting with a box on every goal and taking them out one at a time.
+     * A goal can be emptied when its box can be pulled out to the room entrance, with the
+     * boxes left as they are and the player starting from any zone, it could be anywhere
+     * once the last box is pushed. Emptying a goal only leaves more room for the others, so
+     * a box that can go out still can after others did, and taking the first one every time
+     * finds an order whenever there is one. The goals emptied last are the first ones to
+     * fill. It is one order out of maybe many, not the only one that works.
+     *
+     *   #######
+     *   #...  #  the goal in the corner has to be filled first, and the one next to the
+     *   ####  #  entrance last, or they would block the way to the others
+     *   #$$$  #
+     *   #  @  #
+     *   #######
+     */
+    pub fn packing_order(&self) -> Option<Vec<usize>> {
+        let room = self.goal_room()?;
+        let mut sokoban = self.reverse();
+        let floor = self.inner_floor(&room);
+        let mut remaining: Vec<usize> = (0..self.goals.len()).collect();
+        let mut order = Vec::new();
+        while !remaining.is_empty() {
+            sokoban.set_boxes(remaining.iter().map(|goal| self.goals[*goal]).collect());
+            let next = (0..remaining.len()).find(|box_index| sokoban.can_leave_room(&room, *box_index, &floor))?;
+            order.push(remaining.remove(next));
+        }
+        order.reverse();
+        Some(order)
+    }
+
+    // Floor the player can get to from the goal room, with only walls
+    fn inner_floor(&self, room: &GoalRoom) -> Vec<Position> {
+        let mut floor = vec![room.entrance];
+        let mut queue = vec![room.entrance];
+        while let Some(current) = queue.pop() {
+            for adjacent in Node::loop_positions(&current).iter() {
+                if !self.is_wall(adjacent) && !floor.contains(adjacent) {
+                    floor.push(*adjacent);
+                    queue.push(*adjacent);
+                }
+            }
+        }
+        floor
+    }
+
+    // Whether the box can be pulled out of the room from some player zone, trying each once
+    fn can_leave_room(&mut self, room: &GoalRoom, box_index: usize, floor: &[Position]) -> bool {
+        let mut zones = HashSet::new();
+        for player in floor.iter() {
+            if self.has_box(player) {
+                continue;
+            }
+            self.player = Some(*player);
+            if !zones.insert(self.player_zone()) {
+                continue;
+            }
+            let is_out = |sbox: &Position, player: &Position| *sbox == room.entrance && !room.area.contains(player);
+            if self.box_path(box_index, is_out).is_some() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packing_order() {
+        let sokoban = Sokoban::from_xsb("#######\n#...  #\n####  #\n#$$$  #\n#  @  #\n#######").unwrap();
+        assert_eq!(sokoban.packing_order(), Some(vec![0, 1, 2]));
+
+        // either goal can be filled first, and the player starting on a goal doesn't matter
+        let sokoban = Sokoban::from_xsb("#######\n#.   +#\n#     #\n### ###\n# $ $ #\n#     #\n#######").unwrap();
+        let mut order = sokoban.packing_order().unwrap();
+        order.sort_unstable();
+        assert_eq!(order, vec![0, 1]);
+
+        // the goals are out in the open
+        let sokoban = Sokoban::from_xsb("######\n#@$. #\n# $. #\n######").unwrap();
+        assert_eq!(sokoban.packing_order(), None);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::limits::LimitReason;
+use crate::node::Position;
+use crate::sokoban::Direction;
+use crate::solution::{Pull, Solution};
+use crate::solver::{Solver, DFS_FRAME_SIZE, MAX_DFS_DEPTH};
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// Stack of the threads running a search, the DFS goes one call deeper for every box move, about
+// 47 MiB with room for the deadlock checks at its deepest state and the calls before it
+pub const SEARCH_STACK_SIZE: usize = MAX_DFS_DEPTH * DFS_FRAME_SIZE + STACK_MARGIN;
+const STACK_MARGIN: usize = 8 * 1024 * 1024;
+
+// A state of the DFS left for another thread to search below, with the moves that got to it
+pub(crate) struct Subtree {
+    boxes: Vec<Position>,
+    player: Position,
+    path: Vec<Pull>,
+    unstored_path: Vec<(usize, Box<[u32]>)>,
+    start_cost: usize,
+    goal_index: usize,
+    box_index: usize,
+    direction: Direction,
+    depth: usize,
+}
+
+struct Subtrees {
+    waiting: VecDeque<Subtree>,
+    // threads searching a subtree, which may still leave parts of it to the others
+    busy: usize,
+}
+
+// Subtrees of a parallel DFS no thread took yet, shared between all of them
+pub(crate) struct WorkQueue {
+    subtrees: Mutex<Subtrees>,
+    changed: Condvar,
+    // threads waiting for a subtree, read without the lock before offering one
+    idle: AtomicUsize,
+}
+
+impl WorkQueue {
+    fn new(subtrees: VecDeque<Subtree>) -> WorkQueue {
+        WorkQueue {
+            subtrees: Mutex::new(Subtrees { waiting: subtrees, busy: 0 }),
+            changed: Condvar::new(),
+            idle: AtomicUsize::new(0),
+        }
+    }
+
+    /*
+     * The next subtree to search, waiting for one while other threads may still leave some.
+     * None once there are none left and no thread is searching, or the search was stopped.
+     */
+    fn take(&self, stop: impl Fn() -> bool) -> Option<Subtree> {
+        let mut subtrees = self.subtrees.lock().unwrap();
+        loop {
+            if stop() {
+                return None;
+            }
+            if let Some(subtree) = subtrees.waiting.pop_front() {
+                subtrees.busy += 1;
+                return Some(subtree);
+            }
+            if subtrees.busy == 0 {
+                return None;
+            }
+            self.idle.fetch_add(1, Ordering::Relaxed);
+            subtrees = self.changed.wait(subtrees).unwrap();
+            self.idle.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Done with a subtree, waking up the threads waiting in case it was the last one
+    fn done(&self) {
+        self.subtrees.lock().unwrap().busy -= 1;
+        self.changed.notify_all();
+    }
+
+    // Queues the subtree when some thread is waiting for one, and no other is queued for it
+    fn offer(&self, subtree: impl FnOnce() -> Subtree) -> bool {
+        if self.idle.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let mut subtrees = self.subtrees.lock().unwrap();
+        if subtrees.waiting.len() >= self.idle.load(Ordering::Relaxed) {
+            return false;
+        }
+        subtrees.waiting.push_back(subtree());
+        self.changed.notify_one();
+        true
+    }
+
+    // Wakes up every thread waiting, the search is over
+    fn stop(&self) {
+        let _subtrees = self.subtrees.lock().unwrap();
+        self.changed.notify_all();
+    }
+}
+
+impl Solver {
+    /*
+     * Runs the DFS on the given number of threads, sharing the states table. Every player zone
+     * starts as a subtree to search, and while some thread has nothing to do, the others leave
+     * it the moves they would go down next instead of searching them. Those moves have gone
+     * through the same macros, ordering and PI-corral filter as the single thread DFS, so the
+     * threads search the same tree between them. The first thread that solves the level stops
+     * the others. There is at most one thread per core, and if some of them can't start the
+     * others search without them. If none can, the search ends on the memory limit.
+     */
+    pub fn solve_parallel(&mut self, threads: usize) -> Option<Solution> {
+        self.share_table(threads);
+        self.stop.store(false, Ordering::Relaxed);
+        let zones: VecDeque<Subtree> = self
+            .start_zones()
+            .into_iter()
+            .map(|player| Subtree {
+                boxes: self.sokoban.boxes().to_vec(),
+                player,
+                path: Vec::new(),
+                unstored_path: Vec::new(),
+                start_cost: 0,
+                goal_index: 0,
+                box_index: 0,
+                direction: Direction::Up,
+                depth: 0,
+            })
+            .collect();
+        // more threads than cores only take turns on them, with a big stack each
+        let threads = threads.clamp(1, thread::available_parallelism().map_or(1, |cores| cores.get()));
+        debug!("searching {} player zones on {} threads", zones.len(), threads);
+
+        let work = Arc::new(WorkQueue::new(zones));
+        let solution = Mutex::new(None);
+        self.work = Some(Arc::clone(&work));
+        let limits = self.limits.split(threads);
+        let finished: Vec<Solver> = thread::scope(|scope| {
+            let mut handles = Vec::new();
+            for _ in 0..threads {
+                let mut worker = self.worker();
+                worker.limits = limits.clone();
+                let solution = &solution;
+                let spawned = thread::Builder::new()
+                    .stack_size(SEARCH_STACK_SIZE)
+                    .spawn_scoped(scope, move || {
+                        worker.search_subtrees(solution);
+                        worker
+                    });
+                // the subtrees are queued, the threads already started get through all of them
+                match spawned {
+                    Ok(handle) => handles.push(handle),
+                    Err(error) => {
+                        debug!("searching on {} threads, no more could start: {}", handles.len(), error);
+                        break;
+                    }
+                }
+            }
+            // the DFS can't run on a stack any smaller
+            if handles.is_empty() {
+                self.limits.reason = Some(LimitReason::Memory);
+            }
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        self.work = None;
+        for worker in finished.iter() {
+            self.counter += worker.counter;
+            self.limits.memory = self.limits.memory.max(worker.limits.memory);
+            self.limits.reason = self.limits.reason.or(worker.limits.reason);
+            self.limits.depth_cut |= worker.limits.depth_cut;
+        }
+        solution.into_inner().unwrap()
+    }
+
+    /*
+     * Runs the search on a thread of its own with SEARCH_STACK_SIZE of stack, which the DFS
+     * needs as it goes one call deeper for every box move. If the thread can't start there is
+     * no memory for the search, and it ends on the memory limit.
+     */
+    pub(crate) fn on_search_stack<F>(&mut self, search: F) -> Option<Solution>
+    where
+        F: FnOnce(&mut Solver) -> Option<Solution> + Send,
+    {
+        let searched = thread::scope(|scope| {
+            thread::Builder::new()
+                .stack_size(SEARCH_STACK_SIZE)
+                .spawn_scoped(scope, || search(self))
+                .map(|handle| handle.join().unwrap())
+        });
+        searched.unwrap_or_else(|error| {
+            debug!("no thread to search on: {}", error);
+            self.limits.reason = Some(LimitReason::Memory);
+            None
+        })
+    }
+
+    // Searches the subtrees queued until there are none left or some thread solved the level
+    fn search_subtrees(&mut self, solution: &Mutex<Option<Solution>>) {
+        let work = Arc::clone(self.work.as_ref().unwrap());
+        let start = self.sokoban.boxes().to_vec();
+        while let Some(subtree) = work.take(|| self.stop.load(Ordering::Relaxed)) {
+            self.sokoban.set_boxes(subtree.boxes);
+            self.sokoban.player = Some(subtree.player);
+            self.path = subtree.path;
+            self.unstored_path = subtree.unstored_path;
+            let solved = self.solve_dfs(
+                subtree.start_cost,
+                subtree.goal_index,
+                subtree.box_index,
+                &subtree.direction,
+                usize::MAX,
+                subtree.depth,
+            );
+            if solved {
+                self.stop.store(true, Ordering::Relaxed);
+                solution.lock().unwrap().get_or_insert_with(|| self.solution());
+            }
+            work.done();
+        }
+        self.sokoban.set_boxes(start);
+        work.stop();
+    }
+
+    /*
+     * Leaves the state the last move got to for another thread, when some thread of a
+     * parallel search is waiting for work. Returns whether it was left.
+     */
+    pub(crate) fn share_subtree(
+        &self,
+        start_cost: usize,
+        goal_index: usize,
+        box_index: usize,
+        direction: &Direction,
+        depth: usize,
+    ) -> bool {
+        let work = match self.work.as_ref() {
+            Some(work) => work,
+            None => return false,
+        };
+        work.offer(|| Subtree {
+            boxes: self.sokoban.boxes().to_vec(),
+            player: self.sokoban.player.unwrap(),
+            path: self.path.clone(),
+            unstored_path: self.unstored_path.clone(),
+            start_cost,
+            goal_index,
+            box_index,
+            direction: *direction,
+            depth,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sokoban::Sokoban;
+
+    #[test]
+    fn test_parallel_solver() {
+        let sokoban_level = String::from("0612111111011111100001110001103300000141103012220001100011111111111110000000");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_parallel(4).unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+        assert!(solver.counter > 0);
+    }
+
+    #[test]
+    fn test_parallel_solver_with_too_many_threads() {
+        // one thread per core at most, with no stack taken for the rest
+        let sokoban_level = String::from("0612111111011111100001110001103300000141103012220001100011111111111110000000");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_parallel(100_000).unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+    }
+
+    #[test]
+    fn test_subtree_left_to_an_idle_thread() {
+        // the only pull of the box is left to a thread waiting for work, which solves the level
+        let sokoban = Sokoban::from_xsb("########|#@$   .#|########").unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        let work = Arc::new(WorkQueue::new(VecDeque::new()));
+        solver.work = Some(Arc::clone(&work));
+        work.idle.store(1, Ordering::Relaxed);
+        assert!(solver.solve_sokoban().is_none());
+        assert_eq!(work.subtrees.lock().unwrap().waiting.len(), 1);
+
+        work.idle.store(0, Ordering::Relaxed);
+        let mut worker = solver.worker();
+        let solution = Mutex::new(None);
+        worker.search_subtrees(&solution);
+        let solution = solution.into_inner().unwrap().unwrap();
+        assert!(solution.verify(&sokoban));
+        assert_eq!(solution.pushes().len(), 4);
+        assert!(work.subtrees.lock().unwrap().waiting.is_empty());
+    }
+
+    #[test]
+    fn test_search_on_its_own_stack() {
+        // far more stack than the thread calling it has is left for the DFS
+        let sokoban_level = String::from("080711111111000001102320110323011023201103230110040011111111");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let small_stack = thread::Builder::new().stack_size(64 * 1024);
+        let solution = small_stack.spawn(move || solver.solve_sokoban()).unwrap().join().unwrap();
+        assert!(solution.unwrap().verify(&Sokoban::new(&sokoban_level).unwrap()));
+    }
+
+    #[test]
+    fn test_parallel_solver_with_player_zones() {
+        // two zones to start pulling from, searched at the same time
+        let sokoban_level = String::from("0706111110104010122210133311100001100001111111");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        assert_eq!(solver.player_zones().len(), 2);
+        let solution = solver.solve_parallel(2).unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+    }
+}
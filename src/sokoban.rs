@@ -1,12 +1,15 @@
+use crate::error::LevelError;
+use crate::grid::{BitSet, Grid};
 use crate::node::{Node, NodeType, Position};
 use log::debug;
 use std::char;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::hash::{Hash, Hasher};
+use std::iter;
 
-#[derive(Clone, Debug)]
+const NUMERIC_V2: &str = "v2:";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Direction {
     Up = 0,
     Down = 1,
@@ -14,6 +17,45 @@ pub enum Direction {
     Right = 3,
 }
 
+impl Direction {
+    pub const ALL: [Direction; 4] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+
+    pub fn opposite(&self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+
+    // Position one step away in this direction, None if it falls out of the grid
+    pub fn step(&self, position: &Position) -> Option<Position> {
+        match self {
+            Direction::Up if position.y > 0 => Some(Position::new(position.x, position.y - 1)),
+            Direction::Down => Some(Position::new(position.x, position.y + 1)),
+            Direction::Left if position.x > 0 => Some(Position::new(position.x - 1, position.y)),
+            Direction::Right => Some(Position::new(position.x + 1, position.y)),
+            _ => None,
+        }
+    }
+
+    // LURD notation, lowercase for moves, uppercase for pushes
+    pub fn to_lurd(&self, is_push: bool) -> char {
+        let lurd = match self {
+            Direction::Up => 'u',
+            Direction::Down => 'd',
+            Direction::Left => 'l',
+            Direction::Right => 'r',
+        };
+        if is_push {
+            lurd.to_ascii_uppercase()
+        } else {
+            lurd
+        }
+    }
+}
+
 impl Iterator for Direction {
     type Item = Direction;
 
@@ -27,53 +69,277 @@ impl Iterator for Direction {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Debug)]
 pub struct Sokoban {
     pub width: usize,
     pub height: usize,
-    pub map: HashMap<Position, NodeType>,
+    // walls, goals and floor, boxes and player are kept apart
+    pub map: Grid,
+    // positions with a box, follows boxes the same way the hash does
+    box_mask: BitSet,
+    // squares a box can't get to a goal from, see find_dead_squares
+    pub(crate) dead_squares: BitSet,
+    // boxes are pulled instead of pushed
+    pub(crate) reversed: bool,
     player_reachable: Option<Vec<Vec<u8>>>,
+    // box hash and box positions player_reachable was built for, and the player zone it holds
+    reachable_zone: Option<(u64, BitSet, Position)>,
+    // what player_reachable was before every box move, so undoing it gets it back
+    reachable_history: Vec<ReachableState>,
+    // a key for every position with a box, followed by a key for every player zone
+    zobrist: Vec<u64>,
+    box_hash: u64,
     pub player: Option<Position>,
     pub goals: Vec<Position>,
-    pub boxes: Vec<Position>,
+    // moved through move_box, set_box or set_boxes only, so the hash follows them
+    boxes: Vec<Position>,
+}
+
+// Same level, boxes and player, whatever the caches picked up while searching
+impl PartialEq for Sokoban {
+    fn eq(&self, other: &Sokoban) -> bool {
+        self.map == other.map
+            && self.reversed == other.reversed
+            && self.boxes == other.boxes
+            && self.player == other.player
+    }
+}
+
+impl Eq for Sokoban {}
+
+// player_reachable with the box hash, box positions and player zone it was built for
+type ReachableState = (Option<Vec<Vec<u8>>>, Option<(u64, BitSet, Position)>);
+
+// splitmix64 over the position index, the same keys on every run
+fn zobrist_keys(count: usize) -> Vec<u64> {
+    let mut seed: u64 = 0;
+    (0..count)
+        .map(|_| {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut key = seed;
+            key = (key ^ (key >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            key = (key ^ (key >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            key ^ (key >> 31)
+        })
+        .collect()
 }
 
 impl Sokoban {
-    pub fn new(level: &String) -> Sokoban {
-        Sokoban::build(&NodeType::build, level)
+    pub fn new(level: &str) -> Result<Sokoban, LevelError> {
+        let (height, width, tiles) = Sokoban::parse_level(level)?;
+        Sokoban::build(&NodeType::build, height, width, &tiles)
+    }
+
+    /*
+     * Builds a sokoban world from the standard XSB text format, rows can be separated by
+     * new lines or '|' and can have different lengths, the missing tiles are set as empty.
+     *
+     *     #####
+     *   ###.  #
+     *   #.$@$.#
+     *   #######
+     */
+    pub fn from_xsb(level: &str) -> Result<Sokoban, LevelError> {
+        let rows: Vec<&str> = level
+            .split(['\n', '|'])
+            .map(|row| row.trim_end_matches('\r'))
+            .filter(|row| !row.trim().is_empty())
+            .collect();
+        let height = rows.len();
+        let width = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+
+        let mut tiles = vec![0; height * width];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                let ntype = NodeType::from_xsb(tile)
+                    .map_err(|_| LevelError::InvalidTile { x, y, ch: tile })?;
+                tiles[y * width + x] = ntype as u32;
+            }
+        }
+        Sokoban::build(&NodeType::build, height, width, &tiles)
+    }
+
+    pub fn to_xsb(&self) -> String {
+        let mut rows = Vec::new();
+        for y in 0..self.height {
+            let mut row = String::new();
+            for x in 0..self.width {
+                row.push_str(&self.get_ntype(&Position { x, y }).to_string());
+            }
+            rows.push(row.trim_end().to_string());
+        }
+        rows.join("\n")
     }
 
     pub fn can_reach(&mut self, position: &Position) -> bool {
-        self.get_hash();
+        self.update_player_reachable();
         self.player_reachable.as_ref().unwrap()[position.x][position.y] == 1
     }
 
-    pub fn new_reverse(level: &String) -> Sokoban {
-        Sokoban::build(&NodeType::reverse_build, level)
+    // Top left most position the player can reach, the same for every position in the zone
+    pub fn player_zone(&mut self) -> Position {
+        self.update_player_reachable();
+        self.reachable_zone.as_ref().unwrap().2
     }
 
+    pub fn new_reverse(level: &str) -> Result<Sokoban, LevelError> {
+        let (height, width, tiles) = Sokoban::parse_level(level)?;
+        let mut sokoban = Sokoban::build(&NodeType::reverse_build, height, width, &tiles)?;
+        sokoban.reversed = true;
+        sokoban.dead_squares = sokoban.find_dead_squares();
+        Ok(sokoban)
+    }
+
+    // Same world as new_reverse would build, boxes are swapped with goals
+    pub fn reverse(&self) -> Sokoban {
+        let mut map = Grid::new(self.width, self.height);
+        for (position, ntype) in self.map.iter() {
+            let ntype = if ntype == NodeType::Wall {
+                NodeType::Wall
+            } else if self.has_box(&position) {
+                NodeType::Whole
+            } else {
+                NodeType::Empty
+            };
+            map.insert(position, ntype);
+        }
+
+        let player = match self.player {
+            Some(player) if self.goals.contains(&player) => None,
+            player => player,
+        };
+        let mut sokoban = Sokoban {
+            height: self.height,
+            width: self.width,
+            map,
+            box_mask: self.box_mask.clone(),
+            dead_squares: BitSet::new(self.width * self.height),
+            reversed: !self.reversed,
+            player_reachable: None,
+            reachable_zone: None,
+            reachable_history: Vec::new(),
+            zobrist: self.zobrist.clone(),
+            box_hash: 0,
+            player,
+            boxes: Vec::new(),
+            goals: self.boxes.clone(),
+        };
+        sokoban.set_boxes(self.goals.clone());
+        sokoban.dead_squares = sokoban.find_dead_squares();
+        sokoban
+    }
+
+    /*
+     * Zobrist hash of the state, the keys of every box position xor the key of the player
+     * zone. The box part is kept up to date as boxes move, and the player zone is only
+     * searched again when the boxes changed or the player left the zone it had.
+     */
     pub fn get_hash(&mut self) -> u64 {
-        // This is a quick fix, ideally we want to do one of these 2 things:
-        // 1.- Separate the player_reachable state matrix into a history so we can recover it
-        // 2.- Make the changes stateless, so we pass a copy of sokoban to the next depth DFS level
-        // this way, we remove the need to do undo_move_box
-        let mut player_reachable = self.init_player_reachable();
-        self.build_player_reachable(&self.player.unwrap(), &mut player_reachable);
-        self.player_reachable = Some(player_reachable);
-        let mut hasher = DefaultHasher::new();
-        Hash::hash_slice(self.player_reachable.as_ref().unwrap(), &mut hasher);
-        hasher.finish()
-    }
-
-    fn parse_level(level: &str) -> (usize, usize, &str) {
-        (
-            (&level[0..2]).parse::<usize>().unwrap(),
-            (&level[2..4]).parse::<usize>().unwrap(),
-            &level[4..],
-        )
+        let zone = self.player_zone();
+        self.box_hash ^ self.zobrist[self.width * self.height + self.position_index(&zone)]
+    }
+
+    // The state get_hash comes from, the player zone followed by the sorted box positions
+    pub fn packed_state(&mut self) -> Box<[u32]> {
+        let zone = self.player_zone();
+        let mut state: Vec<u32> = iter::once(&zone)
+            .chain(self.boxes.iter())
+            .map(|position| self.position_index(position) as u32)
+            .collect();
+        state[1..].sort_unstable();
+        state.into_boxed_slice()
+    }
+
+    pub(crate) fn position_index(&self, position: &Position) -> usize {
+        position.y * self.width + position.x
+    }
+
+    pub fn has_box(&self, position: &Position) -> bool {
+        self.map.index(position).is_some_and(|index| self.box_mask.contains(index))
+    }
+
+    pub fn boxes(&self) -> &[Position] {
+        &self.boxes
+    }
+
+    pub fn set_box(&mut self, box_index: usize, position: Position) {
+        let (previous, next) = (self.position_index(&self.boxes[box_index]), self.position_index(&position));
+        self.box_hash ^= self.zobrist[previous] ^ self.zobrist[next];
+        self.box_mask.remove(previous);
+        self.box_mask.insert(next);
+        self.boxes[box_index] = position;
+    }
+
+    pub fn set_boxes(&mut self, boxes: Vec<Position>) {
+        self.box_hash = 0;
+        self.box_mask.clear();
+        for sbox in boxes.iter() {
+            let index = self.position_index(sbox);
+            self.box_hash ^= self.zobrist[index];
+            self.box_mask.insert(index);
+        }
+        self.boxes = boxes;
+    }
+
+    /*
+     * Numeric levels come in two versions:
+     * - "HHWW" followed by HH * WW digits, the original format limited to 99x99 levels
+     * - "v2:" followed by rows of digits separated by '|', rows can have different lengths
+     *   and the missing tiles are set as empty, i.e. "v2:1111|1421|1311|111"
+     */
+    fn parse_level(level: &str) -> Result<(usize, usize, Vec<u32>), LevelError> {
+        if let Some(level) = level.strip_prefix(NUMERIC_V2) {
+            return Sokoban::parse_level_v2(level);
+        }
+
+        let header = level.get(0..4).ok_or_else(|| LevelError::InvalidHeader(level.to_string()))?;
+        let invalid_header = |_| LevelError::InvalidHeader(header.to_string());
+        let height = header[0..2].parse::<usize>().map_err(invalid_header)?;
+        let width = header[2..4].parse::<usize>().map_err(invalid_header)?;
+
+        let mut tiles = Vec::with_capacity(height * width);
+        for (index, tile) in level[4..].chars().take(height * width).enumerate() {
+            let (x, y) = (index % width, index / width);
+            match tile.to_digit(10) {
+                Some(tile) => tiles.push(tile),
+                None => return Err(LevelError::InvalidTile { x, y, ch: tile }),
+            }
+        }
+
+        if tiles.len() < height * width {
+            return Err(LevelError::TruncatedLevel {
+                expected: height * width,
+                found: tiles.len(),
+            });
+        }
+        Ok((height, width, tiles))
     }
 
+    fn parse_level_v2(level: &str) -> Result<(usize, usize, Vec<u32>), LevelError> {
+        let rows: Vec<&str> = level.split('|').collect();
+        let height = rows.len();
+        let width = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+        if width == 0 {
+            return Err(LevelError::TruncatedLevel { expected: 1, found: 0 });
+        }
+
+        let mut tiles = vec![0; height * width];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                tiles[y * width + x] = tile
+                    .to_digit(10)
+                    .ok_or(LevelError::InvalidTile { x, y, ch: tile })?;
+            }
+        }
+        Ok((height, width, tiles))
+    }
+
+    // Original "HHWW" format when the level fits in it, else the "v2:" format
     pub fn print_level(&self) -> String { 
+        if self.height > 99 || self.width > 99 {
+            return self.print_level_v2();
+        }
+
         let mut level = String::new();
         for y in 0..self.height {
             for x in 0..self.width {
@@ -84,17 +350,42 @@ impl Sokoban {
         format!("{:02}{:02}{}", self.height, self.width, level)
     }
 
-    fn build(func: &dyn Fn(u32) -> Result<NodeType, &'static str>, level: &str) -> Sokoban {
-        let (height, width, level) = Sokoban::parse_level(level);
+    pub fn print_level_v2(&self) -> String {
+        let mut rows = Vec::new();
+        for y in 0..self.height {
+            let mut row = String::new();
+            for x in 0..self.width {
+                let ntype = self.get_ntype(&Position { x, y }) as usize;
+                row.push_str(&ntype.to_string());
+            }
+            rows.push(row);
+        }
+        format!("{}{}", NUMERIC_V2, rows.join("|"))
+    }
+
+    // If the level is in one of the numeric formats instead of XSB
+    pub fn is_numeric(level: &str) -> bool {
+        level.starts_with(NUMERIC_V2) || level.starts_with(|c: char| c.is_ascii_digit())
+    }
 
-        let mut map = HashMap::new();
-        let mut level = level.chars();
+    fn build(
+        func: &dyn Fn(u32) -> Result<NodeType, &'static str>,
+        height: usize,
+        width: usize,
+        tiles: &[u32],
+    ) -> Result<Sokoban, LevelError> {
+        let mut map = Grid::new(width, height);
         let mut player: Option<Position> = None;
         let mut boxes = vec![];
         let mut goals = vec![];
         for y in 0..height {
             for x in 0..width {
-                let mut node_type = func(level.next().unwrap().to_digit(10).unwrap()).unwrap();
+                let tile = tiles[y * width + x];
+                let mut node_type = func(tile).map_err(|_| LevelError::InvalidTile {
+                    x,
+                    y,
+                    ch: char::from_digit(tile, 10).unwrap_or('?'),
+                })?;
                 if node_type.is_player() {
                     player = Some(Position { x, y });
                     if node_type == NodeType::Player {
@@ -118,25 +409,49 @@ impl Sokoban {
                 map.insert(Position { x, y }, node_type);
             }
         }
-        Sokoban {
+
+        // count over the tiles as given, a reverse world may not keep the player
+        let count = |codes: &[u32]| tiles.iter().filter(|tile| codes.contains(tile)).count();
+        match count(&[4, 6]) {
+            0 => return Err(LevelError::NoPlayer),
+            1 => {}
+            players => return Err(LevelError::MultiplePlayers(players)),
+        }
+        let (box_count, goal_count) = (count(&[3, 5]), count(&[2, 5, 6]));
+        if box_count != goal_count {
+            return Err(LevelError::BoxGoalCountMismatch {
+                boxes: box_count,
+                goals: goal_count,
+            });
+        }
+
+        let mut sokoban = Sokoban {
             height,
             width,
             map,
+            box_mask: BitSet::new(width * height),
+            dead_squares: BitSet::new(width * height),
+            reversed: false,
             player_reachable: None,
+            reachable_zone: None,
+            reachable_history: Vec::new(),
+            zobrist: zobrist_keys(2 * width * height),
+            box_hash: 0,
             player,
-            boxes,
+            boxes: Vec::new(),
             goals,
-        }
+        };
+        sokoban.set_boxes(boxes);
+        sokoban.dead_squares = sokoban.find_dead_squares();
+        Ok(sokoban)
     }
 
     pub fn get_ntype(&self, position: &Position) -> NodeType {
-        let ntype = self.map.get(position);
-        if ntype.is_none() {
-            return NodeType::Wall;
-        }
-
-        let mut ntype = *ntype.unwrap();
-        if self.boxes.contains(position) {
+        let mut ntype = match self.map.get(position) {
+            Some(ntype) => ntype,
+            None => return NodeType::Wall,
+        };
+        if self.has_box(position) {
             if ntype == NodeType::Whole {
                 ntype = NodeType::BoxOnWhole;
             } else {
@@ -155,45 +470,93 @@ impl Sokoban {
     }
 
     fn init_player_reachable(&self) -> Vec<Vec<u8>> {
-        let mut reachable = vec![vec![0 as u8; self.height]; self.width];
-        for y in 0..self.height {
-            for x in 0..self.width {
+        let mut reachable = vec![vec![0_u8; self.height]; self.width];
+        for sbox in self.boxes.iter() {
+            reachable[sbox.x][sbox.y] = self.get_ntype(sbox) as u8;
+        }
+        reachable
+    }
 
-                let ntype = self.get_ntype(&Position{ x, y });
-                if ntype.is_box() {
-                    reachable[x][y] = ntype as u8;
-                }
+    fn update_player_reachable(&mut self) {
+        let player = self.player.unwrap();
+        // two sets of boxes can share a hash, the area is only kept for the same boxes
+        if let (Some(reachable), Some((box_hash, box_mask, _))) = (&self.player_reachable, &self.reachable_zone) {
+            if *box_hash == self.box_hash && *box_mask == self.box_mask && reachable[player.x][player.y] == 1 {
+                return;
             }
         }
-        reachable
+
+        let mut reachable = self.init_player_reachable();
+        self.build_player_reachable(&player, &mut reachable);
+        let zone = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| Position { x, y }))
+            .find(|position| reachable[position.x][position.y] == 1)
+            .unwrap_or(player);
+        self.player_reachable = Some(reachable);
+        self.reachable_zone = Some((self.box_hash, self.box_mask.clone(), zone));
     }
 
-    fn build_player_reachable(&self, current: &Position, reachable: &mut Vec<Vec<u8>>) {
+    // Marks every square the player can walk to from start, with a stack of squares left to
+    // look at instead of recursion, a big level would take a deep call stack
+    fn build_player_reachable(&self, start: &Position, reachable: &mut [Vec<u8>]) {
         // XXX If we can push a box, we can move, this may be buggy, need an edge case unit test!
-
-        //If player has alredy been here, we dont care about getting here again.
-        if reachable[current.x][current.y] == 1 {
-            return;
+        let mut pending = vec![*start];
+        while let Some(current) = pending.pop() {
+            //If player has alredy been here, we dont care about getting here again.
+            if reachable[current.x][current.y] == 1 || !self.get_ntype(&current).can_move() {
+                continue;
+            }
+            reachable[current.x][current.y] = 1;
+            pending.extend(Node::loop_positions(&current).iter());
         }
+    }
 
-        if self.get_ntype(current).can_move() {
-            reachable[current.x][current.y] = 1;
+    /*
+     * Breadth first search over the map to get the shortest walk between two positions,
+     * boxes are taken as obstacles since the player is only walking.
+     */
+    pub fn shortest_path(&self, from: &Position, to: &Position) -> Option<Vec<Direction>> {
+        let mut came_from: HashMap<Position, (Position, Direction)> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(*from);
+        while let Some(current) = queue.pop_front() {
+            if current == *to {
+                let mut path = Vec::new();
+                let mut current = current;
+                while current != *from {
+                    let (previous, direction) = came_from[&current];
+                    path.push(direction);
+                    current = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
 
-            for adjacent in Node::loop_positions(&current).iter() {
-                self.build_player_reachable(&adjacent, reachable);
+            for direction in Direction::ALL.iter() {
+                let adjacent = match direction.step(&current) {
+                    Some(adjacent) => adjacent,
+                    None => continue,
+                };
+                if adjacent == *from || came_from.contains_key(&adjacent) {
+                    continue;
+                }
+                if !self.get_ntype(&adjacent).can_move() {
+                    continue;
+                }
+                came_from.insert(adjacent, (current, *direction));
+                queue.push_back(adjacent);
             }
         }
+        None
     }
 
     pub fn is_resolved(&mut self) -> bool {
-        let boxes: HashSet<_> = self.boxes.iter().collect();
-        let goals: HashSet<_> = self.goals.iter().collect();
-        boxes == goals
+        self.boxes.len() == self.goals.len() && self.goals.iter().all(|goal| self.has_box(goal))
     }
 
     pub fn get_future_position(&self, box_position: &Position, direction: &Direction) -> Result<(Position, Position), &'static str> {
-        let mut new_box_pos = box_position.clone();
-        let mut new_player_pos = box_position.clone();
+        let mut new_box_pos = *box_position;
+        let mut new_player_pos = *box_position;
 
         match direction {
             Direction::Up => {
@@ -222,8 +585,16 @@ impl Sokoban {
         Ok((new_box_pos, new_player_pos))
     }
 
+    /*
+     * Moves a box one square if the player can get to where it needs to be, on reversed
+     * worlds the box is pulled and the player ends up past it, else the box is pushed and
+     * the player takes its place.
+     */
     pub fn move_box(&mut self, box_index: usize, direction: &Direction) -> bool {
         // debug!("Trying {:?} on box {}", direction, box_index);
+        if !self.reversed {
+            return self.push_box(box_index, direction);
+        }
 
         let box_position = self.boxes[box_index];
         let future_result = self.get_future_position(&box_position, direction);
@@ -236,7 +607,8 @@ impl Sokoban {
         let player_future = self.get_ntype(&player_position);
 
         if box_future.can_move() && player_future.can_move() && self.can_reach(&box_position) {
-            self.boxes[box_index] = box_position;
+            self.reachable_history.push((self.player_reachable.take(), self.reachable_zone.take()));
+            self.set_box(box_index, box_position);
             self.player = Some(player_position);
             debug!(
                 "moved box: {} player: {}, {:?} {} {:?}",
@@ -247,6 +619,22 @@ impl Sokoban {
         false
     }
 
+    fn push_box(&mut self, box_index: usize, direction: &Direction) -> bool {
+        let box_position = self.boxes[box_index];
+        let (box_future, player_side) = match (direction.step(&box_position), direction.opposite().step(&box_position)) {
+            (Some(box_future), Some(player_side)) => (box_future, player_side),
+            _ => return false,
+        };
+
+        if self.get_ntype(&box_future).can_move() && self.can_reach(&player_side) {
+            self.reachable_history.push((self.player_reachable.take(), self.reachable_zone.take()));
+            self.set_box(box_index, box_future);
+            self.player = Some(box_position);
+            return true;
+        }
+        false
+    }
+
     pub fn undo_move_box(&mut self, box_index: usize, direction: &Direction) {
         // println!("Undoing {:?} on box {} {}", direction, box_index, self);
         let mut box_position = self.boxes[box_index];
@@ -266,7 +654,15 @@ impl Sokoban {
             }
         };
 
-        self.boxes[box_index] = box_position;
+        // a pushed box goes back to where the player was, with the player behind it
+        if !self.reversed {
+            self.player = direction.opposite().step(&box_position);
+        }
+        self.set_box(box_index, box_position);
+        if let Some((reachable, zone)) = self.reachable_history.pop() {
+            self.player_reachable = reachable;
+            self.reachable_zone = zone;
+        }
     }
 }
 
@@ -280,7 +676,7 @@ impl fmt::Display for Sokoban {
             for x in 0..self.width {
                 let position = Position { x, y };
                 grid_display.push_str(&format!("{}", self.get_ntype(&position)));
-                grid_display.push_str(" ");
+                grid_display.push(' ');
             }
         }
         write!(f, "{}", grid_display)
@@ -290,21 +686,49 @@ impl fmt::Display for Sokoban {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_env_logger;
 
     #[test]
     fn test_build_sokoban_world() {
         let sokoban_level =
             String::from("080711111111200001110320101011011401101113230101000100111110");
-        let sokoban = Sokoban::new(&sokoban_level);
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
         println!("{}", sokoban);
     }
 
+    #[test]
+    fn test_build_sokoban_errors() {
+        assert_eq!(Sokoban::new(&String::from("05")).err(), Some(LevelError::InvalidHeader(String::from("05"))));
+        assert_eq!(
+            Sokoban::new(&String::from("050611111112210113310114000111")).err(),
+            Some(LevelError::TruncatedLevel { expected: 30, found: 26 })
+        );
+        assert_eq!(
+            Sokoban::new(&String::from("0506111111122101139101140001111111")).err(),
+            Some(LevelError::InvalidTile { x: 2, y: 2, ch: '9' })
+        );
+        assert_eq!(
+            Sokoban::new(&String::from("0506111111122101133101100001111111")).err(),
+            Some(LevelError::NoPlayer)
+        );
+        assert_eq!(
+            Sokoban::new(&String::from("0506111111122101133101140041111111")).err(),
+            Some(LevelError::MultiplePlayers(2))
+        );
+        assert_eq!(
+            Sokoban::new(&String::from("0506111111122101130101140001111111")).err(),
+            Some(LevelError::BoxGoalCountMismatch { boxes: 1, goals: 2 })
+        );
+        assert_eq!(
+            Sokoban::from_xsb("####|#@x#|####").err(),
+            Some(LevelError::InvalidTile { x: 2, y: 1, ch: 'x' })
+        );
+    }
+
     #[test]
     fn test_reverse_build_sokoban_world() {
         let sokoban_level =
-            String::from("080711111111200001110620101011011001101113230101000100111110");
-        Sokoban::new_reverse(&sokoban_level);
+            String::from("080711111111233001110620101011011001101113230101000100111110");
+        Sokoban::new_reverse(&sokoban_level).unwrap();
         // println!("{}", sokoban);
     }
 
@@ -319,9 +743,9 @@ mod tests {
         let sokoban_level1 = String::from("0506111111120101100101140301111111");
         let sokoban_level2 = String::from("0506111111120101104101100301111111");
         let sokoban_level3 = String::from("0506111111120101100101100341111111");
-        let mut sokoban = Sokoban::new(&sokoban_level1);
-        let mut sokoban2 = Sokoban::new(&sokoban_level2);
-        let mut sokoban3 = Sokoban::new(&sokoban_level3);
+        let mut sokoban = Sokoban::new(&sokoban_level1).unwrap();
+        let mut sokoban2 = Sokoban::new(&sokoban_level2).unwrap();
+        let mut sokoban3 = Sokoban::new(&sokoban_level3).unwrap();
         debug!("{}", sokoban);
         debug!("{}", sokoban2);
         debug!("{}", sokoban3);
@@ -332,16 +756,16 @@ mod tests {
         //moved box: (5, 5)
         let sokoban_level1 = String::from("08081111111110300001100022011111101100010310000104100001001000011110");
         let sokoban_level2 = String::from("08081111111110300001100022011111141100010310000100100001001000011110");
-        let mut sokoban1 = Sokoban::new(&sokoban_level1);
-        let mut sokoban2 = Sokoban::new(&sokoban_level2);
+        let mut sokoban1 = Sokoban::new(&sokoban_level1).unwrap();
+        let mut sokoban2 = Sokoban::new(&sokoban_level2).unwrap();
         println!("{}", sokoban1);
         println!("{}", sokoban2);
         assert_ne!(sokoban1.get_hash(), sokoban2.get_hash());
 
         let sokoban_level1 = String::from("07070111100114011110300011325201100011111001000111100");
         let sokoban_level2 = String::from("07070111100110011110340011055201100011111001000111100");
-        let mut sokoban1 = Sokoban::new(&sokoban_level1);
-        let mut sokoban2 = Sokoban::new(&sokoban_level2);
+        let mut sokoban1 = Sokoban::new(&sokoban_level1).unwrap();
+        let mut sokoban2 = Sokoban::new(&sokoban_level2).unwrap();
         sokoban1.get_hash();
         sokoban2.get_hash();
         println!("{:?}", sokoban1.player_reachable);
@@ -349,25 +773,154 @@ mod tests {
         assert_ne!(sokoban1.get_hash(), sokoban2.get_hash());
     }
 
+    #[test]
+    fn test_push_box() {
+        let mut sokoban = Sokoban::from_xsb("######\n#@$ .#\n######").unwrap();
+        assert!(!sokoban.move_box(0, &Direction::Left));
+        assert!(sokoban.move_box(0, &Direction::Right));
+        assert_eq!(sokoban.boxes, vec![Position::new(3, 1)]);
+        assert_eq!(sokoban.player, Some(Position::new(2, 1)));
+        assert!(sokoban.move_box(0, &Direction::Right));
+        assert!(sokoban.is_resolved());
+
+        sokoban.undo_move_box(0, &Direction::Right);
+        sokoban.undo_move_box(0, &Direction::Right);
+        assert_eq!(sokoban.boxes, vec![Position::new(2, 1)]);
+        assert_eq!(sokoban.player, Some(Position::new(1, 1)));
+    }
+
+    #[test]
+    fn test_hash_follows_moves() {
+        let mut sokoban = Sokoban::new_reverse("0706111100102100100111154001100301100111111100").unwrap();
+        let start = sokoban.get_hash();
+        for box_index in 0..sokoban.boxes.len() {
+            for direction in Direction::ALL.iter() {
+                let player = sokoban.player;
+                if !sokoban.move_box(box_index, direction) {
+                    continue;
+                }
+
+                let moved = sokoban.get_hash();
+                let mut rebuilt = sokoban.clone();
+                rebuilt.set_boxes(sokoban.boxes.clone());
+                rebuilt.player_reachable = None;
+                assert_eq!(moved, rebuilt.get_hash());
+                assert_ne!(moved, start);
+
+                sokoban.undo_move_box(box_index, direction);
+                sokoban.player = player;
+                assert_eq!(sokoban.get_hash(), start);
+            }
+        }
+    }
+
+    #[test]
+    fn test_equal_whatever_the_caches() {
+        let start = Sokoban::new_reverse("0706111100102100100111154001100301100111111100").unwrap();
+        let mut sokoban = start.clone();
+        sokoban.get_hash();
+        assert!(sokoban.move_box(0, &Direction::Down));
+        assert_ne!(sokoban, start);
+        sokoban.undo_move_box(0, &Direction::Down);
+        sokoban.player = start.player;
+        assert_eq!(sokoban, start);
+    }
+
+    #[test]
+    fn test_reachable_area_on_hash_collision() {
+        let mut sokoban = Sokoban::from_xsb("######\n#@$ .#\n######").unwrap();
+        assert!(!sokoban.can_reach(&Position::new(2, 1)));
+        // other boxes with the same hash, the area walled in by the old ones isn't kept
+        let box_hash = sokoban.box_hash;
+        sokoban.set_boxes(vec![Position::new(3, 1)]);
+        sokoban.box_hash = box_hash;
+        assert!(sokoban.can_reach(&Position::new(2, 1)));
+        assert!(!sokoban.can_reach(&Position::new(4, 1)));
+    }
+
     #[test]
     fn test_sokoban_is_resolved() {
         let sokoban_level = String::from("0506111111122101133101140001111111");
-        let mut sokoban = Sokoban::new(&sokoban_level);
+        let mut sokoban = Sokoban::new(&sokoban_level).unwrap();
         println!("{}", sokoban);
 
-        assert_eq!(sokoban.is_resolved(), false);
+        assert!(!sokoban.is_resolved());
 
         let sokoban_level = String::from("0506111111155101100101140001111111");
-        let mut sokoban = Sokoban::new(&sokoban_level);
+        let mut sokoban = Sokoban::new(&sokoban_level).unwrap();
         println!("{}", sokoban);
-        assert_eq!(sokoban.is_resolved(), true);
+        assert!(sokoban.is_resolved());
+    }
+
+    #[test]
+    fn test_shortest_path() {
+        let sokoban_level = String::from("0506111111122101133101140001111111");
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
+        let path = sokoban.shortest_path(&Position::new(1, 3), &Position::new(4, 1));
+        assert_eq!(path, Some(vec![Direction::Right, Direction::Right, Direction::Right, Direction::Up, Direction::Up]));
+        assert_eq!(sokoban.shortest_path(&Position::new(1, 3), &Position::new(2, 1)), None);
+    }
+
+    #[test]
+    fn test_build_sokoban_from_xsb() {
+        let sokoban = Sokoban::from_xsb("  #####\n###.  #\n#.$@$-#\n#######\n").unwrap();
+        assert_eq!(sokoban.width, 7);
+        assert_eq!(sokoban.height, 4);
+        assert_eq!(sokoban.player, Some(Position::new(3, 2)));
+        assert_eq!(sokoban.boxes, vec![Position::new(2, 2), Position::new(4, 2)]);
+        assert_eq!(sokoban.goals, vec![Position::new(3, 1), Position::new(1, 2)]);
+        assert_eq!(sokoban.get_ntype(&Position::new(0, 0)), NodeType::Empty);
+        assert_eq!(sokoban.get_ntype(&Position::new(6, 1)), NodeType::Wall);
+
+        let numeric = Sokoban::from_xsb("####|# .#|#  ###|#*@  #|#  $ #|#  ###|####").unwrap();
+        assert_eq!(numeric.print_level(), "0706111100102100100111154001100301100111111100");
+    }
+
+    #[test]
+    fn test_sokoban_to_xsb() {
+        let level = "  #####\n###.  #\n#.$@$ #\n#######";
+        assert_eq!(Sokoban::from_xsb(level).unwrap().to_xsb(), level);
+        assert_eq!(Sokoban::from_xsb("######|#-@$.#|######").unwrap().to_xsb(), "######\n# @$.#\n######");
+
+        let sokoban = Sokoban::new(&String::from("0506111111122101133101140001111111")).unwrap();
+        assert_eq!(sokoban.to_xsb(), "######\n#..# #\n#$$# #\n#@   #\n######");
+    }
+
+    #[test]
+    fn test_reverse_sokoban() {
+        let sokoban_level = String::from("080711111111233001110620101011011001101113230101000100111110");
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
+        assert_eq!(sokoban.reverse(), Sokoban::new_reverse(&sokoban_level).unwrap());
     }
 
     #[test]
     fn test_sokoban_print_level() {
         let sokoban_level = String::from("0506111111122101133101140001111111");
-        let sokoban = Sokoban::new(&sokoban_level);
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
         assert_eq!(sokoban.print_level(), sokoban_level);
     }
 
+    #[test]
+    fn test_sokoban_level_v2() {
+        let sokoban_level = String::from("v2:111111|122101|133101|140001|111111");
+        let sokoban = Sokoban::new(&sokoban_level).unwrap();
+        assert_eq!(sokoban.print_level(), "0506111111122101133101140001111111");
+        assert_eq!(sokoban.print_level_v2(), sokoban_level);
+
+        let ragged = Sokoban::new(&String::from("v2:0111|0121|111311|14001|11111")).unwrap();
+        assert_eq!(ragged.width, 6);
+        assert_eq!(ragged.print_level_v2(), "v2:011100|012100|111311|140010|111110");
+
+        let corridor = format!("v2:{}|1{}2341|{}", "1".repeat(120), "0".repeat(115), "1".repeat(120));
+        let sokoban = Sokoban::new(&corridor).unwrap();
+        assert_eq!(sokoban.width, 120);
+        assert_eq!(sokoban.print_level(), corridor);
+        assert_eq!(Sokoban::new(&sokoban.print_level()).unwrap(), sokoban);
+
+        assert_eq!(
+            Sokoban::new(&String::from("v2:111|1a1")).err(),
+            Some(LevelError::InvalidTile { x: 1, y: 1, ch: 'a' })
+        );
+    }
+
 }
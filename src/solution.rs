@@ -0,0 +1,300 @@
+use crate::node::Position;
+use crate::sokoban::{Direction, Sokoban};
+
+// A pull made by the reverse solver, the box at box_position moved one step to direction
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pull {
+    pub box_position: Position,
+    pub direction: Direction,
+}
+
+impl Pull {
+    /*
+     * The push undoing the pull, a pull moves the box from P to P+d and the player from P+d
+     * to P+2d, so the forward push has the player on P+2d pushing the box on P+d back to P.
+     */
+    pub fn to_push(&self) -> Push {
+        let box_position = self.direction.step(&self.box_position).unwrap();
+        Push {
+            player: self.direction.step(&box_position).unwrap(),
+            box_position,
+            direction: self.direction.opposite(),
+        }
+    }
+}
+
+// A push in the forward game, the player stands on player and pushes the box at box_position
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Push {
+    pub player: Position,
+    pub box_position: Position,
+    pub direction: Direction,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Step {
+    Walk { from: Position, to: Position },
+    Push(Push),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Solution {
+    pub steps: Vec<Step>,
+}
+
+impl Solution {
+    /*
+     * Turns the pulls found by the reverse solver into forward pushes.
+     *
+     * The last pull is the first push, each of them undone by a push, and the player walks
+     * from wherever the previous push left it to behind the next box.
+     */
+    pub fn from_pulls(player: Position, pulls: &[Pull]) -> Solution {
+        let pushes: Vec<Push> = pulls.iter().rev().map(|pull| pull.to_push()).collect();
+        Solution::from_pushes(player, &pushes)
+    }
+
+    /*
+     * Builds the solution from the pushes found on the level as given, the player walks from
+     * wherever the previous push left it to behind the next box.
+     */
+    pub fn from_pushes(player: Position, pushes: &[Push]) -> Solution {
+        let mut steps = Vec::new();
+        let mut player = player;
+        for push in pushes.iter() {
+            if player != push.player {
+                steps.push(Step::Walk {
+                    from: player,
+                    to: push.player,
+                });
+            }
+            steps.push(Step::Push(*push));
+            player = push.box_position;
+        }
+        Solution { steps }
+    }
+
+    /*
+     * Builds the solution from single player moves, true when the move pushes the box in
+     * front of the player. Consecutive walking moves are joined into one walk.
+     */
+    pub fn from_moves(player: Position, moves: &[(Direction, bool)]) -> Solution {
+        let mut steps = Vec::new();
+        let mut player = player;
+        let mut walk_from = player;
+        for (direction, is_push) in moves.iter() {
+            let next = direction.step(&player).unwrap();
+            if *is_push {
+                if walk_from != player {
+                    steps.push(Step::Walk {
+                        from: walk_from,
+                        to: player,
+                    });
+                }
+                steps.push(Step::Push(Push {
+                    player,
+                    box_position: next,
+                    direction: *direction,
+                }));
+                walk_from = next;
+            }
+            player = next;
+        }
+
+        if walk_from != player {
+            steps.push(Step::Walk {
+                from: walk_from,
+                to: player,
+            });
+        }
+        Solution { steps }
+    }
+
+    pub fn pushes(&self) -> Vec<Push> {
+        self.steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Push(push) => Some(*push),
+                Step::Walk { .. } => None,
+            })
+            .collect()
+    }
+
+    /*
+     * Exports the solution in LURD notation, lowercase letters for the player moves and
+     * uppercase for pushes. Walks are expanded into the shortest path between the two
+     * positions given the boxes placement at that point of the solution.
+     */
+    pub fn to_lurd(&self, sokoban: &Sokoban) -> Option<String> {
+        let mut sokoban = sokoban.clone();
+        let mut lurd = String::new();
+        for step in self.steps.iter() {
+            match step {
+                Step::Walk { from, to } => {
+                    for direction in sokoban.shortest_path(from, to)?.iter() {
+                        lurd.push(direction.to_lurd(false));
+                    }
+                    sokoban.player = Some(*to);
+                }
+                Step::Push(push) => {
+                    let box_index = sokoban.boxes().iter().position(|b| *b == push.box_position)?;
+                    sokoban.set_box(box_index, push.direction.step(&push.box_position)?);
+                    sokoban.player = Some(push.box_position);
+                    lurd.push(push.direction.to_lurd(true));
+                }
+            }
+        }
+        Some(lurd)
+    }
+
+    /*
+     * Replays the solution over a forward sokoban world, checking that every walk can be
+     * done and every push moves a box into a free square, and that it ends solved.
+     */
+    pub fn verify(&self, sokoban: &Sokoban) -> bool {
+        let mut sokoban = sokoban.clone();
+        for step in self.steps.iter() {
+            match step {
+                Step::Walk { from, to } => {
+                    if sokoban.player != Some(*from) || !sokoban.can_reach(to) {
+                        return false;
+                    }
+                    sokoban.player = Some(*to);
+                }
+                Step::Push(push) => {
+                    if sokoban.player != Some(push.player) {
+                        return false;
+                    }
+
+                    let box_index = sokoban.boxes().iter().position(|b| *b == push.box_position);
+                    let box_future = push.direction.step(&push.box_position);
+                    let (box_index, box_future) = match (box_index, box_future) {
+                        (Some(box_index), Some(box_future)) => (box_index, box_future),
+                        _ => return false,
+                    };
+                    if !sokoban.get_ntype(&box_future).can_move() {
+                        return false;
+                    }
+
+                    sokoban.set_box(box_index, box_future);
+                    sokoban.player = Some(push.box_position);
+                }
+            }
+        }
+        sokoban.is_resolved()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulls_to_pushes() {
+        // box pulled from (2, 1) to (3, 1), the player ending on (4, 1)
+        let pulls = vec![Pull {
+            box_position: Position::new(2, 1),
+            direction: Direction::Right,
+        }];
+        let solution = Solution::from_pulls(Position::new(4, 2), &pulls);
+
+        assert_eq!(
+            solution.steps,
+            vec![
+                Step::Walk {
+                    from: Position::new(4, 2),
+                    to: Position::new(4, 1)
+                },
+                Step::Push(Push {
+                    player: Position::new(4, 1),
+                    box_position: Position::new(3, 1),
+                    direction: Direction::Left,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solution_from_pushes() {
+        let pushes = vec![
+            Push {
+                player: Position::new(1, 1),
+                box_position: Position::new(2, 1),
+                direction: Direction::Right,
+            },
+            Push {
+                player: Position::new(3, 2),
+                box_position: Position::new(3, 1),
+                direction: Direction::Up,
+            },
+        ];
+        let solution = Solution::from_pushes(Position::new(1, 1), &pushes);
+
+        assert_eq!(
+            solution.steps,
+            vec![
+                Step::Push(pushes[0]),
+                Step::Walk {
+                    from: Position::new(2, 1),
+                    to: Position::new(3, 2)
+                },
+                Step::Push(pushes[1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solution_from_moves() {
+        let moves = vec![
+            (Direction::Down, false),
+            (Direction::Left, false),
+            (Direction::Up, true),
+            (Direction::Right, false),
+        ];
+        let solution = Solution::from_moves(Position::new(3, 1), &moves);
+
+        assert_eq!(
+            solution.steps,
+            vec![
+                Step::Walk {
+                    from: Position::new(3, 1),
+                    to: Position::new(2, 2)
+                },
+                Step::Push(Push {
+                    player: Position::new(2, 2),
+                    box_position: Position::new(2, 1),
+                    direction: Direction::Up,
+                }),
+                Step::Walk {
+                    from: Position::new(2, 1),
+                    to: Position::new(3, 1)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solution_to_lurd() {
+        // # # # # # #
+        // # . .   @ #
+        // #   $ $   #
+        // #         #
+        // # # # # # #
+        let sokoban = Sokoban::new(&String::from("0506111111122041103301100001111111")).unwrap();
+        let solution = Solution {
+            steps: vec![
+                Step::Walk {
+                    from: Position::new(4, 1),
+                    to: Position::new(2, 3),
+                },
+                Step::Push(Push {
+                    player: Position::new(2, 3),
+                    box_position: Position::new(2, 2),
+                    direction: Direction::Up,
+                }),
+            ],
+        };
+
+        assert_eq!(solution.to_lurd(&sokoban), Some(String::from("ddllU")));
+    }
+}
@@ -1,93 +1,303 @@
-#![allow(dead_code)]
+use crate::corral::CorralCache;
+use crate::error::LevelError;
+use crate::limits::{LimitTracker, SearchLimits};
+use crate::macros::GoalRoom;
+use crate::matching::min_cost_matching;
+use crate::parallel::WorkQueue;
 use crate::node::{Node, NodeType, Position};
 use crate::sokoban::{Direction, Sokoban};
-use log::debug;
-use std::collections::HashMap;
-use std::ops::{Generator, GeneratorState};
-use std::pin::Pin;
+use crate::solution::{Pull, Push, Solution};
+use crate::table::{ShardedTable, TableStats, DEFAULT_TABLE_MEMORY};
+use log::{debug, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// deepest the DFS goes, branches past it are cut so the search never runs out of stack
+pub(crate) const MAX_DFS_DEPTH: usize = 10_000;
+// stack every box move of the DFS takes, measured up to about 2 KiB on debug builds and 640 B on
+// release ones, with a macro on the way, and doubled to leave room for changes
+pub(crate) const DFS_FRAME_SIZE: usize = 4 * 1024;
+// shards of the states table for every thread of a parallel search, few enough threads wait on the same lock
+const SHARDS_PER_THREAD: usize = 16;
+
+// World the DFS searches, the level as given pushing boxes, or the reversed one pulling them
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchDirection {
+    Forward,
+    Reverse,
+}
+
+// What an iterative deepening round went through
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IterationStats {
+    pub cost_limit: usize,
+    pub steps: usize,
+    pub states: usize,
+}
 
 pub struct Solver {
     heuristics: HashMap<usize, HashMap<Position, usize>>,
-    state_map: HashMap<u64, usize>,
+    goal_room: Option<GoalRoom>,
+    // goals in the order they can be filled, empty if there is none
+    packing_order: Vec<Position>,
+    table_memory: usize,
+    // shared with the other threads of a parallel search
+    pub(crate) state_table: Arc<ShardedTable>,
+    // whether corrals can be opened, shared like state_table
+    pub(crate) corral_cache: Arc<CorralCache>,
+    // states in the current path that didn't fit in state_table, with their depth
+    pub(crate) unstored_path: Vec<(usize, Box<[u32]>)>,
     pub sokoban: Sokoban,
-    original_player: Position,
+    // the level as given, before reversing it
+    pub(crate) start: Sokoban,
+    pub(crate) original_player: Position,
+    // box moves from the start, pulls on the reversed world and pushes on the level as given
+    pub(crate) path: Vec<Pull>,
+    next_cost_limit: Option<usize>,
+    pub iterations: Vec<IterationStats>,
     pub counter: usize,
+    // set once the search has to end, some other thread already solved the level or a limit
+    // was reached
+    pub(crate) stop: Arc<AtomicBool>,
+    pub(crate) limits: LimitTracker,
+    // subtrees left for the idle threads of a parallel search, None on a single thread
+    pub(crate) work: Option<Arc<WorkQueue>>,
 }
 
 impl Solver {
-    pub fn new(level: String) -> Solver {
-        let sokoban = Sokoban::new(&level);
-        Solver {
-            heuristics: Solver::build_heuristics(&sokoban),
-            state_map: HashMap::new(),
-            sokoban: Sokoban::new_reverse(&level),
+    pub fn new(level: String) -> Result<Solver, LevelError> {
+        Solver::from_sokoban(Sokoban::new(&level)?)
+    }
+
+    pub fn from_sokoban(sokoban: Sokoban) -> Result<Solver, LevelError> {
+        let problems = sokoban.validate();
+        if !problems.is_empty() {
+            return Err(LevelError::Invalid(problems));
+        }
+
+        let reverse = sokoban.reverse();
+        Ok(Solver {
+            heuristics: Solver::build_heuristics(&reverse),
+            goal_room: sokoban.goal_room(),
+            packing_order: sokoban
+                .packing_order()
+                .map_or_else(Vec::new, |order| order.iter().map(|goal| sokoban.goals[*goal]).collect()),
+            table_memory: DEFAULT_TABLE_MEMORY,
+            state_table: Arc::new(ShardedTable::with_memory(DEFAULT_TABLE_MEMORY, sokoban.boxes().len() + 1, 1)),
+            corral_cache: Arc::new(CorralCache::default()),
+            unstored_path: Vec::new(),
+            sokoban: reverse,
             original_player: sokoban.player.unwrap(),
+            start: sokoban,
+            path: Vec::new(),
+            next_cost_limit: None,
+            iterations: Vec::new(),
             counter: 0,
-        }
+            stop: Arc::new(AtomicBool::new(false)),
+            limits: LimitTracker::new(SearchLimits::default()),
+            work: None,
+        })
     }
 
+    // Moves the box needs to get to the goal, None if it never can
     fn get_heuristic(&self, goal_index: usize, box_index: usize) -> Option<&usize> {
-        let current_goal = self.sokoban.goals[goal_index];
-        self.heuristics[&box_index].get(&current_goal)
+        let current_box = self.sokoban.boxes()[box_index];
+        self.heuristics[&goal_index].get(&current_box)
     }
 
-    fn been_here(&mut self, depth: usize) -> bool {
+    /*
+     * World the DFS and iterative deepening search, the reversed one by default. Pushing
+     * forward starts from the only place the player can be, but has to stay away from
+     * deadlocks that pulling never runs into.
+     */
+    pub fn set_direction(&mut self, direction: SearchDirection) {
+        self.sokoban = match direction {
+            SearchDirection::Forward => self.start.clone(),
+            SearchDirection::Reverse => self.start.reverse(),
+        };
+        self.heuristics = Solver::build_heuristics(&self.sokoban);
+        self.state_table.clear();
+    }
+
+    pub fn direction(&self) -> SearchDirection {
+        if self.sokoban.reversed {
+            SearchDirection::Reverse
+        } else {
+            SearchDirection::Forward
+        }
+    }
+
+    // Memory the states table can take, the default is DEFAULT_TABLE_MEMORY
+    pub fn set_table_memory(&mut self, memory: usize) {
+        self.table_memory = memory;
+        self.share_table(1);
+    }
+
+    // A new states table taking the same memory, split in shards for the given threads
+    pub(crate) fn share_table(&mut self, threads: usize) {
+        let shards = if threads > 1 { threads * SHARDS_PER_THREAD } else { 1 };
+        let key_len = self.sokoban.boxes().len() + 1;
+        self.state_table = Arc::new(ShardedTable::with_memory(self.table_memory, key_len, shards));
+    }
+
+    /*
+     * A solver for another thread of a parallel search, on the same world and sharing the
+     * states table, the corral cache and the stop flag, with a path and counter of its own.
+     */
+    pub(crate) fn worker(&self) -> Solver {
+        Solver {
+            heuristics: self.heuristics.clone(),
+            goal_room: self.goal_room.clone(),
+            packing_order: self.packing_order.clone(),
+            table_memory: self.table_memory,
+            state_table: Arc::clone(&self.state_table),
+            corral_cache: Arc::clone(&self.corral_cache),
+            unstored_path: Vec::new(),
+            sokoban: self.sokoban.clone(),
+            start: self.start.clone(),
+            original_player: self.original_player,
+            path: Vec::new(),
+            next_cost_limit: None,
+            iterations: Vec::new(),
+            counter: 0,
+            stop: Arc::clone(&self.stop),
+            limits: self.limits.clone(),
+            work: self.work.clone(),
+        }
+    }
+
+    pub fn table_stats(&self) -> TableStats {
+        self.state_table.stats()
+    }
+
+    pub(crate) fn been_here(&mut self, depth: usize) -> bool {
         let current_hash = self.sokoban.get_hash();
-        // debug!("been here? {} {} c:{}\n {:?}", depth, current_hash, self.state_map.contains_key(&current_hash), self.state_map);
-        if self.state_map.contains_key(&current_hash) && depth >= self.state_map[&current_hash] {
-            // debug!("been here!");
+        let current_state = self.sokoban.packed_state();
+        if self.state_table.get(current_hash, &current_state).is_some_and(|seen| depth >= seen) {
             return true;
         }
-        self.state_map.insert(current_hash, depth);
+        if self.unstored_path.iter().any(|(_, state)| *state == current_state) {
+            return true;
+        }
+
+        // states the table has no room for are kept while on the path, so we never go around
+        // in circles
+        if let Err(current_state) = self.state_table.insert(current_hash, current_state, depth) {
+            self.unstored_path.push((depth, current_state));
+        }
         false
     }
 
-    fn build_heuristics(sokoban_map: &Sokoban) -> HashMap<usize, HashMap<Position, usize>> {
+    /*
+     * Lower bound of the pulls left to solve the current state, the cost of the cheapest way
+     * to send every box to a goal of its own. None if there is no such way, some boxes can
+     * only get to the same goals, and the state can't be solved.
+     */
+    pub(crate) fn lower_bound(&self) -> Option<usize> {
+        Solver::matching_bound(&self.sokoban, &self.heuristics)
+    }
+
+    // Cheapest matching of the boxes of the world to its goals, with the given distances
+    pub(crate) fn matching_bound(
+        sokoban_map: &Sokoban,
+        heuristics: &HashMap<usize, HashMap<Position, usize>>,
+    ) -> Option<usize> {
+        let costs: Vec<Vec<Option<usize>>> = sokoban_map
+            .boxes()
+            .iter()
+            .map(|sbox| {
+                (0..heuristics.len())
+                    .map(|goal| heuristics[&goal].get(sbox).copied())
+                    .collect()
+            })
+            .collect();
+        min_cost_matching(&costs)
+    }
+
+    // Moves from every square to each goal of the world searched, pulls when it is reversed
+    pub(crate) fn build_heuristics(sokoban_map: &Sokoban) -> HashMap<usize, HashMap<Position, usize>> {
         let mut heuristics = HashMap::new();
-        for sbox in 0..sokoban_map.boxes.len() {
+        for (goal_index, goal) in sokoban_map.goals.iter().enumerate() {
+            // the pulls from a square into a goal are the pushes from the goal to that square
             heuristics.insert(
-                sbox,
-                Solver::heuristic_bfs(&sokoban_map, sokoban_map.boxes[sbox]),
+                goal_index,
+                Solver::push_distances(sokoban_map, *goal, !sokoban_map.reversed),
             );
         }
         heuristics
     }
 
     /*
-     * Gets a count of steps required to get to a box from any point in the
-     * sokoban table by just taking into account walls, not other boxes. i.e.:
+     * Fewest pushes to take a box from `from` to every square it can get to, with only the
+     * walls on the level. Pushes need the player behind the box, and the player can only walk
+     * around the box to another side if there is a way, so a box against a wall can't leave
+     * it. i.e. pushing from x:
      *
      *  ######
-     *  #12#6#
-     *  #x1#5#
-     *  #1234#
+     *  #    #  the top row can't be reached, the player would need to be below the box
+     *  #1x12#
      *  ######
+     *
+     * When pulling, the box is pulled away from `from` instead, which gives the pushes needed
+     * to take a box from every square into `from`.
      */
-    fn heuristic_bfs(sokoban_map: &Sokoban, goal: Position) -> HashMap<Position, usize> {
-        let mut state = Vec::new();
-        let mut queue = Vec::new();
+    pub(crate) fn push_distances(sokoban_map: &Sokoban, from: Position, pulling: bool) -> HashMap<Position, usize> {
         let mut distance = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        distance.insert(from, 0);
+        // the player could be on any side of the box to begin with
+        for side in Direction::ALL.iter() {
+            if side.step(&from).is_some_and(|player| !sokoban_map.is_wall(&player)) && seen.insert((from, *side)) {
+                queue.push_back((from, *side, 0));
+            }
+        }
 
-        queue.insert(0, goal);
-        state.push(goal);
-        distance.insert(goal, 0);
-        while let Some(current) = queue.pop() {
-            for adjacent in Node::loop_positions(&current).iter() {
-                if state.contains(adjacent) {
+        while let Some((sbox, side, pushes)) = queue.pop_front() {
+            for side in Solver::reachable_sides(sokoban_map, sbox, side).into_iter() {
+                // pushing moves the box away from the player, pulling towards it
+                let direction = if pulling { side } else { side.opposite() };
+                let box_future = match direction.step(&sbox) {
+                    Some(box_future) if !sokoban_map.is_wall(&box_future) => box_future,
+                    _ => continue,
+                };
+                if pulling && direction.step(&box_future).is_none_or(|player| sokoban_map.is_wall(&player)) {
                     continue;
                 }
 
-                state.push(*adjacent);
-                if sokoban_map.get_ntype(&adjacent) != NodeType::Wall {
-                    distance.insert(*adjacent, distance.get(&current).unwrap() + 1);
-                    queue.insert(0, *adjacent);
+                distance.entry(box_future).or_insert(pushes + 1);
+                if seen.insert((box_future, side)) {
+                    queue.push_back((box_future, side, pushes + 1));
                 }
             }
         }
-
         distance
     }
 
+    // Sides of the box the player can walk to from the given one, with only walls and the box
+    fn reachable_sides(sokoban_map: &Sokoban, sbox: Position, side: Direction) -> Vec<Direction> {
+        let start = side.step(&sbox).unwrap();
+        let mut visited = HashSet::new();
+        let mut queue = vec![start];
+        visited.insert(start);
+        while let Some(current) = queue.pop() {
+            for direction in Direction::ALL.iter() {
+                if let Some(adjacent) = direction.step(&current) {
+                    if adjacent != sbox && !sokoban_map.is_wall(&adjacent) && visited.insert(adjacent) {
+                        queue.push(adjacent);
+                    }
+                }
+            }
+        }
+        Direction::ALL
+            .iter()
+            .filter(|side| side.step(&sbox).is_some_and(|player| visited.contains(&player)))
+            .copied()
+            .collect()
+    }
+
     /*
      * A function to get the player on different zones to try to solve the world
      *
@@ -96,25 +306,31 @@ impl Solver {
      * #     $   #
      * # # # # # #
      */
-    fn player_zones(&self) -> Vec<Position> {
+    pub(crate) fn player_zones(&self) -> Vec<Position> {
+        Solver::zones_of(&self.sokoban)
+    }
+
+    // Player zones of the given world, each next to some box, or where the player is when
+    // there is no box at all
+    pub(crate) fn zones_of(sokoban_map: &Sokoban) -> Vec<Position> {
         let mut player_zones: Vec<Position> = Vec::new();
-        let mut unvisited: Vec<Position> = self.sokoban.map.iter().map(|(key, _)| *key).collect();
+        let mut unvisited: Vec<Position> = sokoban_map.map.keys().collect();
         let mut queue = Vec::new();
-        for pos in self.sokoban.map.keys() {
-            if self.sokoban.get_ntype(pos) == NodeType::Empty {
-                queue.insert(0, *pos);
+        for pos in sokoban_map.map.keys() {
+            if sokoban_map.get_ntype(&pos) == NodeType::Empty {
+                queue.insert(0, pos);
                 break;
             }
         }
-        debug!("{}", self.sokoban);
+        debug!("{}", sokoban_map);
         loop {
             // check if there is a box within reach, else, we are outside of the world
             let mut has_box = false;
             let mut last = None;
             while let Some(current) = queue.pop() {
-                unvisited.remove_item(&current);
+                unvisited.retain(|p| *p != current);
                 for adjacent in Node::loop_positions(&current).iter() {
-                    let node_type = self.sokoban.get_ntype(&adjacent);
+                    let node_type = sokoban_map.get_ntype(adjacent);
                     if node_type.is_box() {
                         debug!("Found box {} {}", adjacent, node_type);
                         has_box = true;
@@ -123,7 +339,7 @@ impl Solver {
                         continue;
                     }
 
-                    unvisited.remove_item(adjacent);
+                    unvisited.retain(|p| p != adjacent);
                     if node_type.can_move() {
                         debug!("inserting in queue {} with ntype {}", adjacent, node_type);
                         queue.insert(0, *adjacent);
@@ -132,9 +348,9 @@ impl Solver {
                 last = Some(current);
             }
 
-            if has_box && last.is_some() {
-                player_zones.push(last.unwrap());
-                debug!("Found player {}", last.unwrap());
+            if let (true, Some(last)) = (has_box, last) {
+                player_zones.push(last);
+                debug!("Found player {}", last);
             }
 
             if unvisited.is_empty() {
@@ -142,7 +358,7 @@ impl Solver {
             }
 
             for pos in unvisited.iter() {
-                if self.sokoban.get_ntype(&pos).can_move() {
+                if sokoban_map.get_ntype(pos).can_move() {
                     queue.push(*pos);
                     break;
                 }
@@ -153,64 +369,123 @@ impl Solver {
             }
         }
 
+        // nothing to pull, the level is solved wherever the player stands
+        if player_zones.is_empty() {
+            player_zones.extend(sokoban_map.player);
+        }
         player_zones
     }
 
-    pub fn solve_sokoban(&mut self) -> bool {
-        let solved = false;
-        for player in self.player_zones().iter() {
+    pub fn solve_sokoban(&mut self) -> Option<Solution> {
+        self.on_search_stack(Solver::search_zones)
+    }
+
+    // The DFS from every player zone, one after another
+    fn search_zones(&mut self) -> Option<Solution> {
+        for player in self.start_zones().iter() {
             debug!("Trying player {}", player);
-            self.sokoban.player = Some(player.clone());
-            let solved = self.solve_dfs(0, 0, 0, &Direction::Up, usize::max_value(), 0);
+            self.sokoban.player = Some(*player);
+            self.path.clear();
+            let solved = self.solve_dfs(0, 0, 0, &Direction::Up, usize::MAX, 0);
             if solved {
-                return solved;
+                return Some(self.solution());
             }
         }
-        solved
+        None
     }
 
-    fn generator_test(
-        match_length: u8,
-        previous_goal_index: u8,
-        previous_box_index: u8,
-        previous_direction: &Direction,
-    ) {
-        let mut depth_permutation = || {
-            let mut current_goal_index;
-            for i in 0..match_length {
-                current_goal_index = (previous_goal_index + i) % match_length;
-                let mut current_box_index;
-                for j in 0..match_length {
-                    current_box_index = (previous_box_index + j) % match_length;
-
-                    let mut current_direction = previous_direction.clone();
-                    for _dir in 0u8..4 {
-                        yield (
-                            current_goal_index,
-                            current_box_index,
-                            current_direction.clone(),
-                        );
-                        current_direction = current_direction.next().unwrap();
-                    }
+    // Where the player can start, anywhere on the reversed world, where it is otherwise
+    pub(crate) fn start_zones(&self) -> Vec<Position> {
+        if self.sokoban.reversed {
+            self.player_zones()
+        } else {
+            vec![self.original_player]
+        }
+    }
+
+    pub(crate) fn solution(&self) -> Solution {
+        if self.sokoban.reversed {
+            return Solution::from_pulls(self.original_player, &self.path);
+        }
+        Solution::from_pushes(self.original_player, &Solver::to_pushes(&self.path))
+    }
+
+    // Box moves made on the level as given, with the player behind every box
+    pub(crate) fn to_pushes(moves: &[Pull]) -> Vec<Push> {
+        moves
+            .iter()
+            .map(|push| Push {
+                player: push.direction.opposite().step(&push.box_position).unwrap(),
+                box_position: push.box_position,
+                direction: push.direction,
+            })
+            .collect()
+    }
+
+    /*
+     * Iterative deepening over solve_dfs, every round runs the DFS from all player zones with
+     * a cost limit, pruning the states whose pulls done plus lower bound of pulls left go over
+     * it. The next round uses the smallest of those pruned costs as the new limit, so the
+     * first solution found has the minimum number of pushes, and if nothing got pruned there
+     * is no solution at all.
+     */
+    pub fn solve_ida(&mut self) -> Option<Solution> {
+        self.on_search_stack(Solver::deepen)
+    }
+
+    fn deepen(&mut self) -> Option<Solution> {
+        let zones = self.start_zones();
+        let mut cost_limit = usize::MAX;
+        for player in zones.iter() {
+            self.sokoban.player = Some(*player);
+            if let Some(bound) = self.lower_bound() {
+                cost_limit = cost_limit.min(bound);
+            }
+        }
+
+        self.iterations.clear();
+        while cost_limit != usize::MAX {
+            let counter = self.counter;
+            self.next_cost_limit = None;
+            self.state_table.clear();
+            for player in zones.iter() {
+                self.sokoban.player = Some(*player);
+                self.path.clear();
+                self.unstored_path.clear();
+                if self.solve_dfs(0, 0, 0, &Direction::Up, cost_limit, 0) {
+                    self.finish_iteration(cost_limit, counter);
+                    return Some(self.solution());
                 }
             }
-        };
 
-        while let GeneratorState::Yielded((goal_index, box_index, direction)) =
-            Pin::new(&mut depth_permutation).resume(())
-        {
-            println!(
-                "goal {}, box {}, direction {:?}",
-                goal_index, box_index, direction
-            );
+            self.finish_iteration(cost_limit, counter);
+            if self.limits.reason.is_some() {
+                return None;
+            }
+            cost_limit = self.next_cost_limit.unwrap_or(usize::MAX);
         }
+        None
     }
 
-    fn is_solved(&mut self) -> bool {
-        self.sokoban.is_resolved() && self.sokoban.can_reach(&self.original_player)
+    fn finish_iteration(&mut self, cost_limit: usize, counter: usize) {
+        let stats = IterationStats {
+            cost_limit,
+            steps: self.counter - counter,
+            states: self.state_table.len(),
+        };
+        info!(
+            "iteration with cost limit {}: {} steps, {} states",
+            stats.cost_limit, stats.steps, stats.states
+        );
+        self.iterations.push(stats);
     }
 
-    fn solve_dfs(
+    pub(crate) fn is_solved(&mut self) -> bool {
+        // pulling ends wherever the player is, it has to be able to walk back to its start
+        self.sokoban.is_resolved() && (!self.sokoban.reversed || self.sokoban.can_reach(&self.original_player))
+    }
+
+    pub(crate) fn solve_dfs(
         &mut self,
         start_cost: usize,
         goal_index: usize,
@@ -219,81 +494,142 @@ impl Solver {
         cost_limit: usize,
         depth: usize,
     ) -> bool {
+        if self.stop.load(Ordering::Relaxed) || self.limit_reached(0) {
+            return false;
+        }
+        // the search goes on, but can't tell anymore that there is no solution
+        if depth > MAX_DFS_DEPTH {
+            self.limits.depth_cut = true;
+            return false;
+        }
         if self.is_solved() {
             return true;
         }
 
+        // check if minimum cost is greater than cost limit, keeping the smallest one that is
+        // so the next iterative deepening round knows where to go
+        let minimum_cost = match self.lower_bound() {
+            Some(bound) => start_cost + bound,
+            None => return false,
+        };
+        if minimum_cost > cost_limit {
+            self.next_cost_limit = Some(self.next_cost_limit.map_or(minimum_cost, |limit| limit.min(minimum_cost)));
+            return false;
+        }
+
+        if self.sokoban.is_deadlock(box_index) || self.sokoban.is_corral_deadlock(box_index, &self.corral_cache) {
+            return false;
+        }
+
         if self.been_here(depth) {
             return false;
         }
 
-        // box and goal length are the same, so we can use the match_length for both
-        let match_length = self.sokoban.boxes.len();
-        let mut minimum_cost;
-        let mut current_box_index;
-        for j in 0..match_length {
-            current_box_index = (box_index + j) % match_length;
-            let mut current_goal_index;
-
-            for i in 0..match_length {
-                // second loop over goals
-                current_goal_index = (goal_index + i) % match_length;
-
-                let mut current_direction = previous_direction.clone();
-                let mut is_blocked = true;
-                for _dir in 0..4 {
-                    // Avoid trying to go to a goal where a box is set
-                    // check if minimum cost is greater than cost limit
-                    let heuristic_value = self.get_heuristic(current_goal_index, current_box_index);
-                    let heuristic_value = match heuristic_value {
-                        Some(value) => value,
-                        None => continue,
-                    };
-                    minimum_cost = start_cost + heuristic_value;
-                    if minimum_cost > cost_limit {
-                        break;
-                    }
+        let solved = self.move_boxes(start_cost, goal_index, box_index, previous_direction, cost_limit, depth);
+        if self.unstored_path.last().is_some_and(|(path_depth, _)| *path_depth == depth) {
+            self.unstored_path.pop();
+        }
+        solved
+    }
 
-                    // try to move box, if we can, count, and issue DFS again
-                    if self.sokoban.move_box(current_box_index, &current_direction) {
-                        is_blocked = false;
-                        self.counter += 1;
-                        // debug!("depth: {}, state: {}\n {}", depth, self.sokoban.get_hash(), self.sokoban.print_level());
-                        let solved = self.solve_dfs(
-                            start_cost + 1,
-                            current_goal_index,
-                            current_box_index,
-                            &current_direction,
-                            cost_limit,
-                            depth + 1,
-                        );
-
-                        self.sokoban
-                            .undo_move_box(current_box_index, &current_direction);
-                        // debug!("counter: {}, {}", self.counter, &self.sokoban);
-
-                        if solved == true {
-                            // add path solution
-                            // add box swaps
-                            return true;
-                        }
-                    }
+    /*
+     * Tries every box move from the current state, pulls on the reversed world and pushes on
+     * the level as given, going deeper with solve_dfs on each of them. Moves go in move_order,
+     * a box that can't move in any direction is left out for the goals after.
+     *
+     * Without a cost limit moves are joined into macros. A box moved into a tunnel is taken
+     * all the way through it, and the goal room boxes go straight between their goal and the
+     * room entrance, pulled out of the room or pushed into the next goal to fill. A goal room
+     * macro only goes first, the single moves of its box are tried after it. Iterative
+     * deepening moves one box at a time, macros could skip over the solution with the fewest
+     * pushes.
+     *
+     * With macros, moves follow the packing order, pulling first the box on the next goal to
+     * empty and pushing first to the next goal to fill. States that fill the goals in another
+     * order are not pruned, the order found is only one of maybe many and could cut the only
+     * solution. Pushing, a PI-corral leaves only its own pushes worth trying.
+     *
+     * On a parallel search, a move is left to some thread waiting for work instead of going
+     * deeper with it.
+     */
+    fn move_boxes(
+        &mut self,
+        start_cost: usize,
+        goal_index: usize,
+        box_index: usize,
+        previous_direction: &Direction,
+        cost_limit: usize,
+        depth: usize,
+    ) -> bool {
+        let macros = cost_limit == usize::MAX;
+        let corral_moves = self.sokoban.pi_corral().map(|(_, moves)| moves);
+        let box_order = self.box_order(box_index, macros);
+        let goal_order = self.goal_order(goal_index, macros);
+        let match_length = box_order.len();
+        // directions tried on every box, and whether any of them moved it
+        let mut tried = vec![0; match_length];
+        let mut moved = vec![false; match_length];
+        for (current_goal_index, current_box_index, current_direction) in
+            Solver::move_order(&goal_order, &box_order, *previous_direction)
+        {
+            // Avoid trying to go to a goal the box can't get to, or a box that can't move
+            if self.get_heuristic(current_goal_index, current_box_index).is_none()
+                || (tried[current_box_index] >= 4 && !moved[current_box_index])
+            {
+                continue;
+            }
+            if macros && corral_moves.is_none() && tried[current_box_index] == 0 {
+                // the box may still need to stop short of the macro, or wait for the others, so
+                // its single moves are tried after it
+                if self.goal_room_macro(start_cost, goal_index, current_box_index, cost_limit, depth) == Some(true) {
+                    return true;
+                }
+            }
+            tried[current_box_index] += 1;
+
+            let allowed = corral_moves
+                .as_ref()
+                .is_none_or(|moves| moves.contains(&(current_box_index, current_direction)));
 
-                    current_direction = current_direction.next().unwrap();
+            // try to move box, if we can, count, and issue DFS again
+            let first = self.path.len();
+            if allowed && self.apply_move(current_box_index, &current_direction) {
+                moved[current_box_index] = true;
+                let mut moves = 1;
+                while macros
+                    && self.sokoban.is_tunnel(&self.sokoban.boxes()[current_box_index], &current_direction)
+                    && self.apply_move(current_box_index, &current_direction)
+                {
+                    moves += 1;
                 }
+                // debug!("depth: {}, state: {}\n {}", depth, self.sokoban.get_hash(), self.sokoban.print_level());
+                let solved = self.search_below(
+                    start_cost + moves,
+                    current_goal_index,
+                    current_box_index,
+                    &current_direction,
+                    cost_limit,
+                    depth + 1,
+                );
 
-                if is_blocked {
-                    // check if the box is in a whole
-                    // check if it wasn't able to move because of walls, compared to boxes that
-                    // could be moved in the future.
-                    //
-                    // If can't move because of boxes, break the current loop
-                    // If can't move because of walls, cut the options tree entirely by returning false
-                    if self.should_cut_tree(box_index) {
-                        return false;
-                    }
+                self.undo_moves(current_box_index, first, moves, solved);
+                // debug!("counter: {}, {}", self.counter, &self.sokoban);
 
-                    break;
+                if solved {
+                    // add box swaps
+                    return true;
+                }
+            }
+
+            if tried[current_box_index] == 4 && !moved[current_box_index] {
+                // check if the box is in a whole
+                // check if it wasn't able to move because of walls, compared to boxes that
+                // could be moved in the future.
+                //
+                // If can't move because of boxes, skip the box for the other goals
+                // If can't move because of walls, cut the options tree entirely by returning false
+                if self.should_cut_tree(box_index) {
+                    return false;
                 }
             }
         }
@@ -301,6 +637,146 @@ impl Solver {
         false
     }
 
+    /*
+     * Order in which to try the moves after a given one, every goal, then every box, then
+     * every direction, the goals and boxes in the order given and the directions in turn
+     * from the previous one.
+     */
+    fn move_order<'a>(
+        goal_order: &'a [usize],
+        box_order: &'a [usize],
+        previous_direction: Direction,
+    ) -> impl Iterator<Item = (usize, usize, Direction)> + 'a {
+        goal_order.iter().flat_map(move |goal| {
+            box_order.iter().flat_map(move |sbox| {
+                iter::successors(Some(previous_direction), |direction| { *direction }.next())
+                    .take(4)
+                    .map(move |direction| (*goal, *sbox, direction))
+            })
+        })
+    }
+
+    // Goes on with solve_dfs below the move just made, unless it was left to another thread
+    fn search_below(
+        &mut self,
+        start_cost: usize,
+        goal_index: usize,
+        box_index: usize,
+        direction: &Direction,
+        cost_limit: usize,
+        depth: usize,
+    ) -> bool {
+        if self.share_subtree(start_cost, goal_index, box_index, direction, depth) {
+            return false;
+        }
+        self.solve_dfs(start_cost, goal_index, box_index, direction, cost_limit, depth)
+    }
+
+    /*
+     * Boxes in turn from the one moved last. Ranked on the reversed world, the box on the goal
+     * the packing order fills last is pulled first, every box off the room goals after them.
+     */
+    fn box_order(&self, box_index: usize, ranked: bool) -> Vec<usize> {
+        let match_length = self.sokoban.boxes().len();
+        let mut box_order: Vec<usize> = (0..match_length).map(|j| (box_index + j) % match_length).collect();
+        if ranked && self.sokoban.reversed {
+            box_order.sort_by_key(|index| {
+                let sbox = self.sokoban.boxes()[*index];
+                self.packing_order.iter().rev().position(|goal| *goal == sbox).unwrap_or(match_length)
+            });
+        }
+        box_order
+    }
+
+    /*
+     * Goals in turn from the last one. Ranked on the level as given, the empty goals go in the
+     * packing order, boxes that can get to the next goal to fill are pushed first.
+     */
+    fn goal_order(&self, goal_index: usize, ranked: bool) -> Vec<usize> {
+        let match_length = self.sokoban.goals.len();
+        let mut goal_order: Vec<usize> = (0..match_length).map(|i| (goal_index + i) % match_length).collect();
+        if ranked && !self.sokoban.reversed {
+            goal_order.sort_by_key(|index| {
+                let goal = self.sokoban.goals[*index];
+                let rank = self.packing_order.iter().position(|packed| *packed == goal);
+                (self.sokoban.has_box(&goal), rank.unwrap_or(match_length))
+            });
+        }
+        goal_order
+    }
+
+    /*
+     * Takes the box between the goal room and its entrance at once. Pulling, a box in the room
+     * goes out to the entrance, with the player out of the room. Pushing, a box just pushed
+     * onto the entrance goes to the next goal to fill. None if the box isn't there or can't
+     * get through.
+     */
+    fn goal_room_macro(
+        &mut self,
+        start_cost: usize,
+        goal_index: usize,
+        box_index: usize,
+        cost_limit: usize,
+        depth: usize,
+    ) -> Option<bool> {
+        let room = self.goal_room.as_ref()?;
+        let sbox = self.sokoban.boxes()[box_index];
+        let directions = if self.sokoban.reversed {
+            if !room.area.contains(&sbox) {
+                return None;
+            }
+            let is_out = |sbox: &Position, player: &Position| *sbox == room.entrance && !room.area.contains(player);
+            self.sokoban.box_path(box_index, is_out)?
+        } else {
+            if sbox != room.entrance || self.sokoban.player.is_none_or(|player| room.area.contains(&player)) {
+                return None;
+            }
+            let goal = *self.packing_order.iter().find(|goal| !self.sokoban.has_box(goal))?;
+            self.sokoban.box_path(box_index, |sbox, _| *sbox == goal)?
+        };
+
+        let first = self.path.len();
+        for direction in directions.iter() {
+            self.apply_move(box_index, direction);
+        }
+
+        let last_direction = *directions.last().unwrap();
+        let solved = self.search_below(
+            start_cost + directions.len(),
+            goal_index,
+            box_index,
+            &last_direction,
+            cost_limit,
+            depth + 1,
+        );
+        self.undo_moves(box_index, first, directions.len(), solved);
+        Some(solved)
+    }
+
+    // Moves the box one step, adding it to the path
+    pub(crate) fn apply_move(&mut self, box_index: usize, direction: &Direction) -> bool {
+        let box_position = self.sokoban.boxes()[box_index];
+        if !self.sokoban.move_box(box_index, direction) {
+            return false;
+        }
+        self.counter += 1;
+        self.path.push(Pull {
+            box_position,
+            direction: *direction,
+        });
+        true
+    }
+
+    // Undoes the moves of the box from first on the path, leaving them when they solved the level
+    pub(crate) fn undo_moves(&mut self, box_index: usize, first: usize, moves: usize, keep_path: bool) {
+        for step in self.path[first..first + moves].iter().rev() {
+            self.sokoban.undo_move_box(box_index, &step.direction);
+        }
+        if !keep_path {
+            self.path.truncate(first);
+        }
+    }
+
     /*
      * in case of a blockage, i.e. a box can't be moved
      * check the reason why.
@@ -310,21 +786,24 @@ impl Solver {
      * - If box can't move because there are walls, stop looking that tree.
      */
     fn should_cut_tree(&self, box_index: usize) -> bool {
-        let box_position = self.sokoban.boxes[box_index];
+        let box_position = self.sokoban.boxes()[box_index];
         let box_ntype = self.sokoban.get_ntype(&box_position);
 
         if box_ntype == NodeType::BoxOnWhole {
             return false;
         }
         
-        let directions = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
-        for dir in directions.iter() {
-            let future_result = self.sokoban.get_future_position(&box_position, &dir);
-            if future_result.err().is_some() {
-                continue;
-            }
-
-            let (box_future, player_future) = future_result.ok().unwrap();
+        for dir in Direction::ALL.iter() {
+            // pushing, the player stands behind the box instead of past where it goes
+            let future_result = if self.sokoban.reversed {
+                self.sokoban.get_future_position(&box_position, dir).ok()
+            } else {
+                dir.step(&box_position).zip(dir.opposite().step(&box_position))
+            };
+            let (box_future, player_future) = match future_result {
+                Some(future) => future,
+                None => continue,
+            };
             let box_future = self.sokoban.get_ntype(&box_future);
             let player_future = self.sokoban.get_ntype(&player_future);
             if player_future == NodeType::Wall || box_future == NodeType::Wall {
@@ -341,35 +820,214 @@ impl Solver {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::limits::SolveOutcome;
+    use crate::validate::Problem;
     use pretty_env_logger;
 
     #[test]
     fn test_build_heuristics() {
         let sokoban_level = String::from("0506111111120101130101140001111111");
-        let solver = Solver::new(sokoban_level);
+        let solver = Solver::new(sokoban_level).unwrap();
         println!("{}", solver.sokoban);
         println!("{:?}", solver.heuristics);
     }
 
+    #[test]
+    fn test_push_distances() {
+        let sokoban = Sokoban::from_xsb("######\n#    #\n# $@.#\n######").unwrap();
+        let pushes = Solver::push_distances(&sokoban, Position::new(2, 2), false);
+        assert_eq!(pushes.get(&Position::new(1, 2)), Some(&1));
+        assert_eq!(pushes.get(&Position::new(4, 2)), Some(&2));
+        // walking there takes a step, but the box can't be pushed away from the bottom wall
+        assert_eq!(pushes.get(&Position::new(2, 1)), None);
+
+        let pulls = Solver::push_distances(&sokoban, Position::new(2, 2), true);
+        assert_eq!(pulls.len(), 2);
+        assert_eq!(pulls.get(&Position::new(3, 2)), Some(&1));
+    }
+
     #[test]
     fn test_sokoban_with_multiple_player_zones() {
         pretty_env_logger::init();
         let sokoban_level =
-            String::from("080711111111200601110020101011011001101113230101020100111110");
-        let solver = Solver::new(sokoban_level);
+            String::from("080711111111233601110020101011011301101113230101020100111110");
+        let solver = Solver::new(sokoban_level).unwrap();
 
         assert_eq!(solver.player_zones().len(), 2);
 
         let sokoban_level = String::from("0706111110104010122210133311100001100001111111");
-        let solver = Solver::new(sokoban_level);
+        let solver = Solver::new(sokoban_level).unwrap();
 
         assert_eq!(solver.player_zones().len(), 2);
     }
 
+    #[test]
+    fn test_solver_rejects_invalid_level() {
+        let sokoban_level = String::from("0506111111122101133101140000111111");
+        match Solver::new(sokoban_level) {
+            Err(LevelError::Invalid(problems)) => {
+                assert_eq!(problems, vec![Problem::OpenLevel(Position::new(5, 3))])
+            }
+            _ => panic!("expected an invalid level"),
+        }
+    }
+
+    #[test]
+    fn test_sokoban_xsb_solver() {
+        let level = "####\n# .#\n#  ###\n#*@  #\n#  $ #\n#  ###\n####";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+    }
+
+    #[test]
+    fn test_sokoban_ida_solver() {
+        let sokoban_level = String::from("0706111100102100100111154001100301100111111100");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_ida().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+        assert_eq!(solution.pushes().len(), 8);
+
+        let limits: Vec<usize> = solver.iterations.iter().map(|stats| stats.cost_limit).collect();
+        assert_eq!(limits.last(), Some(&8));
+        assert!(limits.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_sokoban_solver_past_goal_room_macro() {
+        // pulled straight out, the box on the right goal leaves the player in the dead end past
+        // the entrance, it has to go up and around to come out from above
+        let level = "#########|#.     ##|#      ##|#     ###|#   .   #|##### ###|##  $ $ #|##   @  #|#########";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+    }
+
+    #[test]
+    fn test_sokoban_solver_with_many_packing_orders() {
+        // either goal can be filled first
+        let level = "#######|#.   .#|#     #|### ###|# $ $ #|#  @  #|#######";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        assert_eq!(solver.packing_order.len(), 2);
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+
+        solver.set_direction(SearchDirection::Forward);
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+    }
+
+    #[test]
+    fn test_sokoban_solver_follows_packing_order() {
+        // the goal in the corner is filled first, so the box on the other one is pulled first
+        let level = "#######|#.  . #|#     #|### ###|# $ $ #|#  @  #|#######";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        assert_eq!(solver.packing_order, vec![Position::new(1, 1), Position::new(4, 1)]);
+        assert_eq!(solver.box_order(0, false), vec![0, 1]);
+        assert_eq!(solver.box_order(0, true), vec![1, 0]);
+
+        // the first pull is the last push of the solution
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+        let last = solution.pushes().last().copied().unwrap();
+        assert_eq!(last.direction.step(&last.box_position), Some(Position::new(4, 1)));
+    }
+
+    #[test]
+    fn test_sokoban_solver_with_small_table() {
+        let sokoban_level = String::from("0612111111011111100001110001103300000141103012220001100011111111111110000000");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        solver.set_table_memory(2048);
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+        assert!(solver.table_stats().dropped > 0);
+    }
+
+    #[test]
+    fn test_move_order() {
+        let order: Vec<_> = Solver::move_order(&[1, 0], &[0, 1], Direction::Left).collect();
+        assert_eq!(order.len(), 16);
+        assert_eq!(order[0], (1, 0, Direction::Left));
+        assert_eq!(order[1], (1, 0, Direction::Right));
+        assert_eq!(order[2], (1, 0, Direction::Up));
+        assert_eq!(order[3], (1, 0, Direction::Down));
+        assert_eq!(order[4], (1, 1, Direction::Left));
+        assert_eq!(order[8], (0, 0, Direction::Left));
+        assert_eq!(order[15], (0, 1, Direction::Down));
+    }
+
+    #[test]
+    fn test_sokoban_solver_without_boxes() {
+        // nothing to move, every search finds the level solved instead of unsolvable
+        let sokoban = Sokoban::from_xsb("####|#@ #|####").unwrap();
+        let searches: [fn(&mut Solver) -> Option<Solution>; 5] = [
+            Solver::solve_sokoban,
+            Solver::solve_ida,
+            Solver::solve_push_optimal,
+            Solver::solve_bidirectional,
+            |solver| solver.solve_parallel(2),
+        ];
+        for search in searches.iter() {
+            let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+            assert_eq!(solver.player_zones(), vec![Position::new(1, 1)]);
+            match solver.solve_within(search) {
+                SolveOutcome::Solved(solution) => assert!(solution.pushes().is_empty()),
+                outcome => panic!("expected the level solved, got {:?}", outcome),
+            }
+        }
+    }
+
     #[test]
     fn test_sokoban_solver() {
         let sokoban_level = String::from("0706111100102100100111154001100301100111111100");
-        let mut solver = Solver::new(sokoban_level);
-        assert_eq!(solver.solve_sokoban(), true);
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+    }
+
+    #[test]
+    fn test_sokoban_forward_solver_with_pi_corral() {
+        // the goals are fenced by the three boxes, pushing them in are the only moves tried
+        let level = "#######\n#  $ .#\n# @$. #\n#  $ .#\n#######";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        solver.set_direction(SearchDirection::Forward);
+        let (_, pushes) = solver.sokoban.pi_corral().unwrap();
+        assert_eq!(pushes.len(), 3);
+        assert!(pushes.iter().all(|(_, direction)| *direction == Direction::Right));
+
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+    }
+
+    #[test]
+    fn test_sokoban_forward_solver_past_goal_room_macro() {
+        // the packing order fills the lower goal first, pulling the upper box out into the dead
+        // end past the entrance, so the macro pushes the first box in to the wrong goal
+        let level = "#########|##   @  #|##  $ $ #|##### ###|####.   #|####. ###|####   ##|####   ##|#########";
+        let sokoban = Sokoban::from_xsb(level).unwrap();
+        let mut solver = Solver::from_sokoban(sokoban.clone()).unwrap();
+        assert_eq!(solver.packing_order[0], Position::new(4, 5));
+        solver.set_direction(SearchDirection::Forward);
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&sokoban));
+    }
+
+    #[test]
+    fn test_sokoban_forward_solver() {
+        // pushing the box into the gap above it is no tunnel, the player can still walk around
+        let sokoban_level = String::from("0607111111110000011010101120354110001111111100");
+        let mut solver = Solver::new(sokoban_level.clone()).unwrap();
+        solver.set_direction(SearchDirection::Forward);
+        assert_eq!(solver.direction(), SearchDirection::Forward);
+        let solution = solver.solve_sokoban().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
+
+        let solution = solver.solve_ida().unwrap();
+        assert!(solution.verify(&Sokoban::new(&sokoban_level).unwrap()));
     }
 }
@@ -0,0 +1,276 @@
+use std::mem;
+use std::sync::Mutex;
+
+// 64 MiB, enough for a few million states on Microban sized levels
+pub const DEFAULT_TABLE_MEMORY: usize = 64 * 1024 * 1024;
+
+const ENTRIES_PER_BUCKET: usize = 2;
+const INITIAL_BUCKETS: usize = 1024;
+
+// A state seen by the search, key is the player zone followed by the sorted box positions
+struct Entry {
+    hash: u64,
+    key: Box<[u32]>,
+    depth: usize,
+}
+
+type Bucket = [Option<Entry>; ENTRIES_PER_BUCKET];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TableStats {
+    pub hits: usize,
+    pub misses: usize,
+    // states left out or taken out once the table was full
+    pub dropped: usize,
+    pub entries: usize,
+}
+
+/*
+ * Depth at which every state was first reached, looked up by the state zobrist hash and
+ * checked against the full packed state so two states sharing a hash are never mixed up.
+ *
+ * The table grows until it reaches its memory budget, from then on a new state takes the
+ * place of the deepest state in its bucket if it isn't deeper than it, states close to the
+ * start cut off bigger parts of the search.
+ */
+pub struct TranspositionTable {
+    buckets: Vec<Bucket>,
+    max_buckets: usize,
+    key_len: usize,
+    stats: TableStats,
+}
+
+impl TranspositionTable {
+    // key_len is the length of the packed states to store, used to size the table
+    pub fn with_memory(memory: usize, key_len: usize) -> TranspositionTable {
+        let bucket_size = mem::size_of::<Bucket>() + ENTRIES_PER_BUCKET * key_len * mem::size_of::<u32>();
+        let mut max_buckets = 1;
+        while max_buckets * 2 * bucket_size <= memory {
+            max_buckets *= 2;
+        }
+        TranspositionTable {
+            buckets: Vec::new(),
+            max_buckets,
+            key_len,
+            stats: TableStats::default(),
+        }
+    }
+
+    // Depth the state was stored with, if it is in the table
+    pub fn get(&mut self, hash: u64, key: &[u32]) -> Option<usize> {
+        let depth = if self.buckets.is_empty() {
+            None
+        } else {
+            self.buckets[self.bucket_index(hash)]
+                .iter()
+                .flatten()
+                .find(|entry| entry.hash == hash && *entry.key == *key)
+                .map(|entry| entry.depth)
+        };
+        match depth {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        depth
+    }
+
+    // Gives the key back if the table is full and kept the states it had instead
+    pub fn insert(&mut self, hash: u64, key: Box<[u32]>, depth: usize) -> Result<(), Box<[u32]>> {
+        if self.buckets.is_empty() {
+            self.resize(INITIAL_BUCKETS.min(self.max_buckets));
+        } else if self.stats.entries >= self.buckets.len() && self.buckets.len() < self.max_buckets {
+            self.resize(self.buckets.len() * 2);
+        }
+
+        let index = self.bucket_index(hash);
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.iter_mut().flatten().find(|entry| entry.hash == hash && entry.key == key) {
+            entry.depth = depth;
+            return Ok(());
+        }
+
+        if let Some(slot) = bucket.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Entry { hash, key, depth });
+            self.stats.entries += 1;
+            return Ok(());
+        }
+
+        if self.buckets.len() < self.max_buckets {
+            self.resize(self.buckets.len() * 2);
+            return self.insert(hash, key, depth);
+        }
+
+        self.stats.dropped += 1;
+        let bucket = &mut self.buckets[index];
+        let deepest = bucket.iter_mut().max_by_key(|slot| slot.as_ref().map_or(0, |entry| entry.depth)).unwrap();
+        if deepest.as_ref().is_some_and(|deepest| deepest.depth >= depth) {
+            *deepest = Some(Entry { hash, key, depth });
+            return Ok(());
+        }
+        Err(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.stats.entries = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.entries == 0
+    }
+
+    pub fn stats(&self) -> TableStats {
+        self.stats
+    }
+
+    // Bytes taken by the buckets and the states in them
+    pub fn memory(&self) -> usize {
+        self.buckets.len() * mem::size_of::<Bucket>() + self.stats.entries * self.key_len * mem::size_of::<u32>()
+    }
+
+    fn bucket_index(&self, hash: u64) -> usize {
+        (hash as usize) & (self.buckets.len() - 1)
+    }
+
+    /*
+     * Moves the states to a table twice as big, or to the first buckets. Every bucket splits
+     * in two, its states going to one half or the other by the next bit of their hash, so
+     * they always have room and none is lost, not even the states on the current path.
+     */
+    fn resize(&mut self, buckets: usize) {
+        let old = mem::replace(&mut self.buckets, (0..buckets).map(|_| [None, None]).collect());
+        for entry in old.into_iter().flat_map(|[first, second]| first.into_iter().chain(second)) {
+            let index = self.bucket_index(entry.hash);
+            let slot = self.buckets[index].iter_mut().find(|slot| slot.is_none());
+            *slot.expect("a split bucket has room for the states it had") = Some(entry);
+        }
+    }
+}
+
+/*
+ * A TranspositionTable split by hash into shards, each behind its own lock, so the threads
+ * of a parallel search see the states the others went through and only wait on each other
+ * when they look at the same shard at once. The memory budget is split evenly between them.
+ */
+pub struct ShardedTable {
+    shards: Vec<Mutex<TranspositionTable>>,
+}
+
+impl ShardedTable {
+    pub fn with_memory(memory: usize, key_len: usize, shards: usize) -> ShardedTable {
+        let shards = shards.max(1);
+        ShardedTable {
+            shards: (0..shards)
+                .map(|_| Mutex::new(TranspositionTable::with_memory(memory / shards, key_len)))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, hash: u64, key: &[u32]) -> Option<usize> {
+        self.shard(hash).lock().unwrap().get(hash, key)
+    }
+
+    pub fn insert(&self, hash: u64, key: Box<[u32]>, depth: usize) -> Result<(), Box<[u32]>> {
+        self.shard(hash).lock().unwrap().insert(hash, key, depth)
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats().entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().memory()).sum()
+    }
+
+    // Stats of all the shards added up
+    pub fn stats(&self) -> TableStats {
+        self.shards.iter().fold(TableStats::default(), |total, shard| {
+            let stats = shard.lock().unwrap().stats();
+            TableStats {
+                hits: total.hits + stats.hits,
+                misses: total.misses + stats.misses,
+                dropped: total.dropped + stats.dropped,
+                entries: total.entries + stats.entries,
+            }
+        })
+    }
+
+    // the low bits of the hash pick the bucket inside the shard, the high ones the shard
+    fn shard(&self, hash: u64) -> &Mutex<TranspositionTable> {
+        &self.shards[(hash >> 32) as usize % self.shards.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_checks_full_state() {
+        let mut table = TranspositionTable::with_memory(DEFAULT_TABLE_MEMORY, 3);
+        assert!(table.insert(7, vec![1, 2, 3].into_boxed_slice(), 4).is_ok());
+        assert_eq!(table.get(7, &[1, 2, 3]), Some(4));
+        // same hash, different state
+        assert_eq!(table.get(7, &[1, 2, 4]), None);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.stats().hits, 1);
+        assert_eq!(table.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_table_keeps_memory_budget() {
+        let mut table = TranspositionTable::with_memory(4096, 2);
+        for state in 0..10_000_u32 {
+            let _ = table.insert(state as u64, vec![state, state].into_boxed_slice(), state as usize % 7);
+        }
+        assert!(table.buckets.len() <= table.max_buckets);
+        assert_eq!(table.len(), table.max_buckets * ENTRIES_PER_BUCKET);
+        assert_eq!(table.stats().dropped, 10_000 - table.len());
+
+        // the shallowest states are kept
+        let depths: Vec<usize> = table.buckets.iter().flatten().flatten().map(|entry| entry.depth).collect();
+        assert!(depths.iter().all(|depth| *depth == 0));
+        assert!(table.memory() <= 4096);
+    }
+
+    #[test]
+    fn test_table_growing_keeps_states() {
+        let mut table = TranspositionTable::with_memory(DEFAULT_TABLE_MEMORY, 1);
+        for state in 0..10_000_u32 {
+            assert!(table.insert(state as u64 * 3, vec![state].into_boxed_slice(), 1).is_ok());
+        }
+        assert!(table.buckets.len() > INITIAL_BUCKETS);
+        assert_eq!(table.len(), 10_000);
+        assert_eq!(table.stats().dropped, 0);
+        assert!((0..10_000_u32).all(|state| table.get(state as u64 * 3, &[state]) == Some(1)));
+    }
+
+    #[test]
+    fn test_sharded_table() {
+        let table = ShardedTable::with_memory(DEFAULT_TABLE_MEMORY, 3, 4);
+        for state in 0..8_u64 {
+            assert!(table.insert(state << 32, vec![state as u32, 0, 0].into_boxed_slice(), 1).is_ok());
+        }
+        assert_eq!(table.get(3 << 32, &[3, 0, 0]), Some(1));
+        assert_eq!(table.get(3 << 32, &[4, 0, 0]), None);
+        assert_eq!(table.len(), 8);
+        assert_eq!(table.stats().hits, 1);
+
+        table.clear();
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::node::{Node, NodeType, Position};
+use crate::sokoban::Sokoban;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Problem {
+    BoxGoalCountMismatch { boxes: usize, goals: usize },
+    NoPlayer,
+    // the player can walk out of the level through this position
+    OpenLevel(Position),
+    BoxOutside(Position),
+    GoalOutside(Position),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::BoxGoalCountMismatch { boxes, goals } => {
+                write!(f, "{} boxes but {} goals", boxes, goals)
+            }
+            Problem::NoPlayer => write!(f, "no player"),
+            Problem::OpenLevel(position) => write!(f, "level is not closed by walls at {}", position),
+            Problem::BoxOutside(position) => write!(f, "box at {} is outside the player area", position),
+            Problem::GoalOutside(position) => write!(f, "goal at {} is outside the player area", position),
+        }
+    }
+}
+
+impl Sokoban {
+    /*
+     * Checks the level can be played, returning every problem found:
+     * - There should be as many boxes as goals
+     * - There should be a player, and walls all around the area it can get to
+     * - Boxes and goals should be within that area
+     *
+     * Boxes are taken as floor when looking for the player area since they may be pushed.
+     */
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if self.boxes().len() != self.goals.len() {
+            problems.push(Problem::BoxGoalCountMismatch {
+                boxes: self.boxes().len(),
+                goals: self.goals.len(),
+            });
+        }
+
+        let player = match self.player {
+            Some(player) => player,
+            None => {
+                problems.push(Problem::NoPlayer);
+                return problems;
+            }
+        };
+
+        let mut area = HashSet::new();
+        let mut queue = vec![player];
+        area.insert(player);
+        while let Some(current) = queue.pop() {
+            if current.x == 0 || current.y == 0 || current.x + 1 >= self.width || current.y + 1 >= self.height {
+                problems.push(Problem::OpenLevel(current));
+                continue;
+            }
+
+            for adjacent in Node::loop_positions(&current).iter() {
+                if self.get_ntype(adjacent) != NodeType::Wall && area.insert(*adjacent) {
+                    queue.push(*adjacent);
+                }
+            }
+        }
+
+        for sbox in self.boxes().iter().filter(|sbox| !area.contains(sbox)) {
+            problems.push(Problem::BoxOutside(*sbox));
+        }
+        for goal in self.goals.iter().filter(|goal| !area.contains(goal)) {
+            problems.push(Problem::GoalOutside(*goal));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_level() {
+        let sokoban = Sokoban::from_xsb("  #####\n###.  #\n#.$@$ #\n#######").unwrap();
+        assert_eq!(sokoban.validate(), vec![]);
+
+        let mut sokoban = Sokoban::from_xsb("#####\n#@$ #\n#. #\n####\n#$.#\n####").unwrap();
+        let mut boxes = sokoban.boxes().to_vec();
+        boxes.pop();
+        sokoban.set_boxes(boxes);
+        assert_eq!(
+            sokoban.validate(),
+            vec![
+                Problem::BoxGoalCountMismatch { boxes: 1, goals: 2 },
+                Problem::GoalOutside(Position::new(2, 4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_open_level() {
+        let sokoban = Sokoban::from_xsb("#####\n#@$.\n#####").unwrap();
+        assert_eq!(sokoban.validate(), vec![Problem::OpenLevel(Position::new(4, 1))]);
+
+        let sokoban = Sokoban::from_xsb("####\n#@ #\n####\n#$.#\n####").unwrap();
+        assert_eq!(
+            sokoban.validate(),
+            vec![
+                Problem::BoxOutside(Position::new(1, 3)),
+                Problem::GoalOutside(Position::new(2, 3)),
+            ]
+        );
+    }
+}